@@ -1,379 +1,5476 @@
+mod pe;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result, anyhow, bail};
-use clap::{Parser, Subcommand, ValueEnum};
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use image::{DynamicImage, GenericImageView, Rgba, RgbaImage, imageops, imageops::FilterType};
 
 // ============ Shared helpers ============
 
-fn resize_contain(img: &DynamicImage, size: u32) -> RgbaImage {
+// Set once from --quiet before any command runs.
+static QUIET: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+fn quiet() -> bool {
+    QUIET.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+// All non-error output goes through these so --quiet silences it in one place;
+// errors are printed by `main` regardless.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Error categories that map to distinct process exit codes (see `exit_code`).
+#[derive(Debug)]
+enum IconError {
+    /// The input is of a format or variant this tool cannot handle.
+    Unsupported(String),
+    /// The input is truncated or internally inconsistent.
+    Malformed(String),
+}
+
+impl std::fmt::Display for IconError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IconError::Unsupported(msg) | IconError::Malformed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for IconError {}
+
+// Placement (x, y, width, height) of an image contain-fitted into a size x size square.
+// Any size works, odd ones included: the artwork never exceeds the square, and when the
+// leftover space is odd the extra pixel goes to the right or bottom.
+fn contain_rect(img: &DynamicImage, size: u32) -> (u32, u32, u32, u32) {
     let (w, h) = img.dimensions();
     let scale = (size as f32 / w as f32).min(size as f32 / h as f32);
-    let nw = (w as f32 * scale).round().max(1.0) as u32;
-    let nh = (h as f32 * scale).round().max(1.0) as u32;
-    let resized = img.resize(nw, nh, FilterType::Lanczos3).to_rgba8();
+    let nw = ((w as f32 * scale).round() as u32).clamp(1, size.max(1));
+    let nh = ((h as f32 * scale).round() as u32).clamp(1, size.max(1));
+    (
+        size.saturating_sub(nw) / 2,
+        size.saturating_sub(nh) / 2,
+        nw,
+        nh,
+    )
+}
+
+/// Resampling filter used to scale the source to each size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ResizeFilter {
+    Lanczos3,
+    CatmullRom,
+    Gaussian,
+    Triangle,
+    Nearest,
+    /// Area average: each output pixel is the mean of the source pixels it covers
+    Box,
+}
+
+// Under --auto-filter, sizes up to this many pixels use area averaging.
+const AUTO_FILTER_BOX_MAX: u32 = 32;
+
+// Scale to exactly `w`x`h`; callers have already worked out the aspect-correct size, and
+// the aspect-preserving `resize` could round one side below what they computed.
+fn resample(img: &DynamicImage, w: u32, h: u32, filter: ResizeFilter) -> DynamicImage {
+    let filter = match filter {
+        ResizeFilter::Box if w <= img.width() && h <= img.height() => {
+            return img.thumbnail_exact(w, h);
+        }
+        // Area averaging only shrinks; enlarge with a smooth linear filter instead.
+        ResizeFilter::Box | ResizeFilter::Triangle => FilterType::Triangle,
+        ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        ResizeFilter::CatmullRom => FilterType::CatmullRom,
+        ResizeFilter::Gaussian => FilterType::Gaussian,
+        ResizeFilter::Nearest => FilterType::Nearest,
+    };
+    img.resize_exact(w, h, filter)
+}
+
+fn resize_contain(img: &DynamicImage, size: u32, filter: ResizeFilter) -> RgbaImage {
+    let (dx, dy, nw, nh) = contain_rect(img, size);
+    let resized = resample(img, nw, nh, filter).to_rgba8();
     let mut canvas = RgbaImage::from_pixel(size, size, Rgba([0, 0, 0, 0]));
-    let dx = ((size as i64 - nw as i64) / 2).max(0);
-    let dy = ((size as i64 - nh as i64) / 2).max(0);
-    imageops::replace(&mut canvas, &resized, dx, dy);
+    imageops::replace(&mut canvas, &resized, dx as i64, dy as i64);
     canvas
 }
 
-fn resize_cover(img: &DynamicImage, size: u32) -> RgbaImage {
-    let (w, h) = img.dimensions();
-    let scale = (size as f32 / w as f32).max(size as f32 / h as f32);
-    let nw = (w as f32 * scale).round().max(size as f32) as u32;
-    let nh = (h as f32 * scale).round().max(size as f32) as u32;
-    let resized = img.resize(nw, nh, FilterType::Lanczos3);
-    let rx = ((resized.width() - size) / 2).min(resized.width() - 1);
-    let ry = ((resized.height() - size) / 2).min(resized.height() - 1);
-    imageops::crop_imm(&resized, rx, ry, size, size).to_image()
-}
-
-fn resized_rgba(base: &DynamicImage, size: u32, contain: bool) -> RgbaImage {
-    if contain {
-        resize_contain(base, size)
-    } else {
-        resize_cover(base, size)
-    }
+// Scale to fit inside a size x size box without padding, so the result is only as wide and
+// tall as the artwork (e.g. 256x128 for a 2:1 source at 256px).
+fn resize_fit(img: &DynamicImage, size: u32, filter: ResizeFilter) -> RgbaImage {
+    let (_, _, nw, nh) = contain_rect(img, size);
+    resample(img, nw, nh, filter).to_rgba8()
 }
 
-fn load_image(path: &Path) -> Result<DynamicImage> {
-    image::open(path).with_context(|| format!("Open image {}", path.display()))
+// Cropping the centered square first gives the same framing as scaling then cropping,
+// without a huge intermediate for very wide or tall sources (10000x1 at 1024px).
+fn resize_cover(img: &DynamicImage, size: u32, filter: ResizeFilter) -> RgbaImage {
+    let square = crop_to_square(img, SquareCrop::Center);
+    resample(&square, size, size, filter).to_rgba8()
 }
 
-fn ensure_dir(path: &Path) -> Result<()> {
-    if path.exists() && !path.is_dir() {
-        bail!("{} exists and is not dir", path.display());
+/// How a source is fitted into each square size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Fit {
+    /// Scale to fit inside the square, padding the rest
+    Contain,
+    /// Scale to fill the square, cropping the overflow
+    Cover,
+    /// Contain for sources with transparency, cover for opaque ones
+    Auto,
+    /// Contain and cover side by side, for comparing (PNG sets only)
+    Both,
+}
+
+// Under --fit auto, sources with at least this share of not fully opaque pixels are
+// treated as logos and contained; anything more opaque is treated as a photo and covered.
+const FIT_AUTO_TRANSPARENT_MIN: f64 = 0.01;
+
+fn transparent_share(img: &DynamicImage) -> f64 {
+    if !img.color().has_alpha() {
+        return 0.0;
     }
-    fs::create_dir_all(path).with_context(|| format!("create dir {}", path.display()))
+    let rgba = img.to_rgba8();
+    let count = rgba.pixels().filter(|p| p[3] < 0xFF).count();
+    count as f64 / (rgba.width() as f64 * rgba.height() as f64)
 }
 
-// ============ ICO / ICNS build ============
+/// Which part of a non-square source `--square` keeps.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum SquareCrop {
+    Center,
+    /// Top of a tall source (wide sources are centered)
+    Top,
+    /// Bottom of a tall source (wide sources are centered)
+    Bottom,
+    /// Left side of a wide source (tall sources are centered)
+    Left,
+    /// Right side of a wide source (tall sources are centered)
+    Right,
+}
 
-fn build_ico(source: &DynamicImage, contain: bool, out: &Path) -> Result<()> {
-    use ico::{IconDir, IconDirEntry, IconImage, ResourceType};
-    let sizes: &[u32] = &[16, 24, 32, 48, 64, 128, 256];
-    let mut dir = IconDir::new(ResourceType::Icon);
-    for &s in sizes {
-        let rgba = resized_rgba(source, s, contain);
-        let (w, h) = rgba.dimensions();
-        let icon = IconImage::from_rgba_data(w, h, rgba.into_raw());
-        let entry = IconDirEntry::encode(&icon).with_context(|| format!("encode {}px", s))?;
-        dir.add_entry(entry);
+// Crop the source to a square once, before any per-size fitting.
+fn crop_to_square(img: &DynamicImage, anchor: SquareCrop) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    let side = w.min(h);
+    let (x, y) = match anchor {
+        SquareCrop::Top if h > w => (0, 0),
+        SquareCrop::Bottom if h > w => (0, h - side),
+        SquareCrop::Left if w > h => (0, 0),
+        SquareCrop::Right if w > h => (w - side, 0),
+        _ => ((w - side) / 2, (h - side) / 2),
+    };
+    img.crop_imm(x, y, side, side)
+}
+
+/// Mirror axis for `--flip`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Flip {
+    /// Mirror left to right
+    H,
+    /// Mirror top to bottom
+    V,
+}
+
+fn parse_rotation(s: &str) -> Result<u32, String> {
+    match s.trim_end_matches("deg").parse::<u32>() {
+        Ok(degrees @ (90 | 180 | 270)) => Ok(degrees),
+        _ => Err(format!("invalid rotation '{}', expected 90, 180 or 270", s)),
     }
-    if let Some(parent) = out.parent() {
-        ensure_dir(parent)?;
+}
+
+// Flip, then rotate clockwise, the loaded source before anything else touches it.
+fn transform_source(img: DynamicImage, flip: Option<Flip>, rotate: Option<u32>) -> DynamicImage {
+    let img = match flip {
+        Some(Flip::H) => img.fliph(),
+        Some(Flip::V) => img.flipv(),
+        None => img,
+    };
+    match rotate {
+        Some(90) => img.rotate90(),
+        Some(180) => img.rotate180(),
+        Some(270) => img.rotate270(),
+        _ => img,
     }
-    let mut f = File::create(out).with_context(|| format!("create {}", out.display()))?;
-    dir.write(&mut f)
-        .with_context(|| format!("write ico {}", out.display()))
 }
 
-fn build_icns(source: &DynamicImage, contain: bool, out: &Path) -> Result<()> {
-    use icns::{IconFamily, IconType, Image, PixelFormat};
-    use std::collections::BTreeSet;
-    let all_sizes: &[u32] = &[16, 32, 64, 128, 256, 512, 1024, 32, 64, 256, 512, 1024];
-    let sizes: BTreeSet<u32> = all_sizes.iter().cloned().collect();
-    let mut family = IconFamily::new();
-    for s in sizes {
-        if let Some(icon_type) = IconType::from_pixel_size(s, s) {
-            let rgba = resized_rgba(source, s, contain);
-            let (w, h) = rgba.dimensions();
-            let data = rgba.into_raw();
-            let img = Image::from_data(PixelFormat::RGBA, w, h, data)
-                .with_context(|| format!("img {}px", s))?;
-            family
-                .add_icon_with_type(&img, icon_type)
-                .with_context(|| format!("add {}", s))?;
-        }
+// Pad the source to a centered square once, so every size derives from the same canvas
+// and cover-fitting has nothing left to crop.
+fn pad_to_square(img: &DynamicImage, color: Rgba<u8>) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    let side = w.max(h);
+    let mut canvas = RgbaImage::from_pixel(side, side, color);
+    imageops::overlay(
+        &mut canvas,
+        &img.to_rgba8(),
+        ((side - w) / 2) as i64,
+        ((side - h) / 2) as i64,
+    );
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// Downscale once to fit `base_size` so every target size is derived from the same
+// intermediate instead of from the (possibly huge) original.
+fn downscale_to_base(img: DynamicImage, base_size: u32) -> DynamicImage {
+    if img.width().max(img.height()) <= base_size {
+        return img;
     }
-    if let Some(parent) = out.parent() {
-        ensure_dir(parent)?;
+    img.resize(base_size, base_size, FilterType::Lanczos3)
+}
+
+/// Options controlling how each target size is rendered from the source image.
+#[derive(Clone, Debug)]
+struct RenderOptions {
+    contain: bool,
+    shadow: Option<Shadow>,
+    /// Backplate cover-fitted to each size beneath the artwork.
+    background_image: Option<DynamicImage>,
+    /// Linear gradient painted beneath the artwork at each size.
+    background_gradient: Option<Gradient>,
+    /// Color for the bars left by contain-fitting a non-square source.
+    letterbox: Option<Rgba<u8>>,
+    /// Image whose luminance times alpha is multiplied into each size's alpha.
+    mask: Option<DynamicImage>,
+    /// Fill the RGB of fully transparent pixels from their visible neighbours.
+    alpha_bleed: bool,
+    /// Explicit filter for every size; overrides `auto_filter`.
+    filter: Option<ResizeFilter>,
+    /// Pick the filter per size: box for small sizes, Lanczos3 otherwise.
+    auto_filter: bool,
+    /// Filters for specific sizes; these win over `filter` and `auto_filter`.
+    size_rules: Vec<SizeRule>,
+    /// Sources for specific sizes, used instead of the main source.
+    size_sources: Vec<(u32, DynamicImage)>,
+    /// Scale by nearest neighbour when a size is a whole multiple of the source.
+    pixel_art: bool,
+    /// Keep the source's aspect: each size is the artwork's own bounding rectangle, unpadded.
+    nonsquare: bool,
+    /// Text ribbon drawn across one corner of every size.
+    label: Option<Label>,
+}
+
+/// One `--size-rule` entry: the filter to use for a single target size.
+#[derive(Copy, Clone, Debug)]
+struct SizeRule {
+    size: u32,
+    filter: ResizeFilter,
+}
+
+fn parse_size_rule(s: &str) -> Result<SizeRule, String> {
+    let invalid = || {
+        format!(
+            "invalid size rule '{}', expected SIZE:FILTER (e.g. 16:nearest)",
+            s
+        )
+    };
+    let (size, filter) = s.split_once(':').ok_or_else(invalid)?;
+    let size = size.trim().parse().map_err(|_| invalid())?;
+    let filter = ResizeFilter::from_str(filter.trim(), true)
+        .map_err(|e| format!("invalid size rule '{}': {}", s, e))?;
+    Ok(SizeRule { size, filter })
+}
+
+/// One `--size-src` entry: the image to render a single target size from.
+#[derive(Clone, Debug)]
+struct SizeSource {
+    size: u32,
+    path: PathBuf,
+}
+
+fn parse_size_source(s: &str) -> Result<SizeSource, String> {
+    let invalid = || {
+        format!(
+            "invalid size source '{}', expected SIZE=PATH (e.g. 16=tiny.png)",
+            s
+        )
+    };
+    let (size, path) = s.split_once('=').ok_or_else(invalid)?;
+    let size = size.trim().parse().map_err(|_| invalid())?;
+    if path.is_empty() {
+        return Err(invalid());
     }
-    let mut f = File::create(out).with_context(|| format!("create {}", out.display()))?;
-    family
-        .write(&mut f)
-        .with_context(|| format!("write icns {}", out.display()))
+    Ok(SizeSource {
+        size,
+        path: PathBuf::from(path),
+    })
 }
 
-// Build from a directory of images (various sizes)
-fn build_from_dir(dir: &Path, format: TargetFormat, out: &Path) -> Result<()> {
-    // Map size->path: choose best (exact size) or pick largest for scaling down later.
-    let mut size_map: Vec<(u32, PathBuf)> = Vec::new();
-    for entry in fs::read_dir(dir).with_context(|| format!("read dir {}", dir.display()))? {
-        let entry = entry?;
-        let p = entry.path();
-        if !p.is_file() {
-            continue;
+impl RenderOptions {
+    fn contain() -> Self {
+        RenderOptions {
+            contain: true,
+            shadow: None,
+            background_image: None,
+            background_gradient: None,
+            letterbox: None,
+            mask: None,
+            alpha_bleed: false,
+            filter: None,
+            auto_filter: false,
+            size_rules: Vec::new(),
+            size_sources: Vec::new(),
+            pixel_art: false,
+            nonsquare: false,
+            label: None,
         }
-        if let Some(ext) = p.extension().and_then(|s| s.to_str()) {
-            match ext.to_ascii_lowercase().as_str() {
-                "png" | "jpg" | "jpeg" => {}
-                _ => continue,
-            };
-        } else {
-            continue;
+    }
+
+    // As with `size_rules`, the last source given for a size wins.
+    fn source_for(&self, size: u32) -> Option<&DynamicImage> {
+        self.size_sources
+            .iter()
+            .rev()
+            .find(|(s, _)| *s == size)
+            .map(|(_, img)| img)
+    }
+
+    fn filter_for(&self, size: u32) -> ResizeFilter {
+        // The last rule for a size wins, like repeated flags elsewhere.
+        if let Some(rule) = self.size_rules.iter().rev().find(|r| r.size == size) {
+            return rule.filter;
         }
-        // Extract size from filename like 16.png or icon-32x32.png etc.
-        let fname = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
-        let mut parsed: Option<u32> = None;
-        for token in fname.split(|c: char| !c.is_ascii_digit()) {
-            if token.len() > 0 {
-                if let Ok(v) = token.parse::<u32>() {
-                    if v > 0 {
-                        parsed = Some(v);
-                        break;
-                    }
-                }
-            }
+        match self.filter {
+            Some(filter) => filter,
+            None if self.auto_filter && size <= AUTO_FILTER_BOX_MAX => ResizeFilter::Box,
+            None => ResizeFilter::Lanczos3,
         }
-        if let Some(sz) = parsed {
-            size_map.push((sz, p));
+    }
+
+    // Filter for scaling the artwork itself to `size`. Under --pixel-art, a whole-number
+    // scale factor (1x, 2x, 3x, ...) copies each source pixel into a block with nearest
+    // neighbour, unless a --size-rule names a filter for that size.
+    fn artwork_filter(&self, base: &DynamicImage, size: u32) -> ResizeFilter {
+        let ((from_w, from_h), (to_w, to_h)) = if self.contain {
+            let (_, _, w, h) = contain_rect(base, size);
+            (base.dimensions(), (w, h))
+        } else {
+            let side = base.width().min(base.height());
+            ((side, side), (size, size))
+        };
+        let whole = to_w % from_w == 0 && to_h % from_h == 0 && to_w / from_w == to_h / from_h;
+        let ruled = self.size_rules.iter().any(|r| r.size == size);
+        if self.pixel_art && whole && !ruled {
+            ResizeFilter::Nearest
+        } else {
+            self.filter_for(size)
         }
     }
-    if size_map.is_empty() {
-        bail!("No sized images found in {}", dir.display());
+}
+
+/// Drop shadow parameters; offsets and blur are in pixels at a 256px reference size.
+#[derive(Clone, Debug)]
+struct Shadow {
+    dx: f32,
+    dy: f32,
+    blur: f32,
+    color: Rgba<u8>,
+}
+
+fn parse_hex_color(s: &str) -> Result<Rgba<u8>, String> {
+    let hex = s.trim_start_matches('#');
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "invalid color '{}', expected RRGGBB or RRGGBBAA",
+            s
+        ));
     }
-    // We'll pick a base largest image to scale others if needed.
-    size_map.sort_by_key(|(s, _)| *s);
-    let largest = size_map.last().unwrap().1.clone();
-    let largest_img = load_image(&largest)?;
-    let contain = true; // directory mode assumes contain for padding
-    match format {
-        TargetFormat::Ico => build_ico(&largest_img, contain, out),
-        TargetFormat::Icns => build_icns(&largest_img, contain, out),
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    let alpha = if hex.len() == 8 { byte(6) } else { 0xFF };
+    Ok(Rgba([byte(0), byte(2), byte(4), alpha]))
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("invalid percentage '{}', expected 0-100", s)),
     }
 }
 
-// ============ Extract ============
+fn parse_aspect_ratio(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(ratio) if ratio >= 1.0 && ratio.is_finite() => Ok(ratio),
+        _ => Err(format!(
+            "invalid ratio '{}', expected a number >= 1 (e.g. 1.25)",
+            s
+        )),
+    }
+}
 
-fn extract_ico(path: &Path, out_dir: &Path, debug: bool) -> Result<()> {
-    #[derive(Debug, Clone)]
-    struct DirEntry {
-        width: u8,
-        height: u8,
-        bitcount: u16,
-        bytes_in_res: u32,
-        image_offset: u32,
+// Warn, or fail under --strict, when the source is further from square than `max_ratio`
+// (long side / short side), since the fit will then crop or pad it heavily.
+fn check_aspect(img: &DynamicImage, max_ratio: f32, contain: bool, strict: bool) -> Result<()> {
+    let (w, h) = img.dimensions();
+    let ratio = w.max(h) as f32 / w.min(h) as f32;
+    if ratio <= max_ratio {
+        return Ok(());
     }
-    let mut f = File::open(path).with_context(|| format!("open {}", path.display()))?;
-    let mut header = [0u8; 6];
-    f.read_exact(&mut header)?;
-    if u16::from_le_bytes([header[0], header[1]]) != 0 {
-        bail!("Invalid ICO reserved");
+    let effect = if contain {
+        "letterboxed with transparent padding"
+    } else {
+        "cropped to its center square"
+    };
+    let msg = format!(
+        "source is {}x{} (aspect {:.2}:1, limit {}:1) and will be {}",
+        w, h, ratio, max_ratio, effect
+    );
+    if strict {
+        bail!("{} (--strict)", msg);
     }
-    if u16::from_le_bytes([header[2], header[3]]) != 1 {
-        bail!("Not ICO");
+    diag!("Warning: {}", msg);
+    Ok(())
+}
+
+fn parse_shadow(s: &str) -> Result<Shadow, String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    if parts.len() != 4 {
+        return Err(format!(
+            "invalid shadow '{}', expected dx,dy,blur,RRGGBBAA",
+            s
+        ));
     }
-    let count = u16::from_le_bytes([header[4], header[5]]) as usize;
-    let mut dir = vec![0u8; 16 * count];
-    f.read_exact(&mut dir)?;
-    let mut entries: Vec<DirEntry> = Vec::with_capacity(count);
-    for i in 0..count {
-        let o = i * 16;
-        entries.push(DirEntry {
-            width: dir[o],
-            height: dir[o + 1],
-            bitcount: u16::from_le_bytes([dir[o + 6], dir[o + 7]]),
-            bytes_in_res: u32::from_le_bytes([dir[o + 8], dir[o + 9], dir[o + 10], dir[o + 11]]),
-            image_offset: u32::from_le_bytes([dir[o + 12], dir[o + 13], dir[o + 14], dir[o + 15]]),
-        });
+    let num = |v: &str, name: &str| {
+        v.parse::<f32>()
+            .map_err(|_| format!("invalid shadow {} '{}'", name, v))
+    };
+    let blur = num(parts[2], "blur")?;
+    if blur < 0.0 {
+        return Err(format!("shadow blur must be >= 0, got {}", blur));
     }
-    // pick largest (treat 0 as 256); tie-break by bitcount then bytes
-    let mut best = None;
-    let mut best_key = (0u32, 0u16, 0u32); // (area, bitcount, bytes)
-    for e in &entries {
-        let w = if e.width == 0 { 256 } else { e.width as u32 };
-        let h = if e.height == 0 { 256 } else { e.height as u32 };
-        let area = w * h;
-        let key = (area, e.bitcount, e.bytes_in_res);
-        if key > best_key {
-            best = Some(e.clone());
-            best_key = key;
-            if debug {
-                eprintln!(
-                    "[debug] new best candidate {}x{} bpp={} bytes={}",
-                    w, h, e.bitcount, e.bytes_in_res
-                );
-            }
-        }
+    Ok(Shadow {
+        dx: num(parts[0], "dx")?,
+        dy: num(parts[1], "dy")?,
+        blur,
+        color: parse_hex_color(parts[3])?,
+    })
+}
+
+/// `--background-gradient`: a two-color linear gradient at a CSS-style angle.
+#[derive(Copy, Clone, Debug)]
+struct Gradient {
+    from: Rgba<u8>,
+    to: Rgba<u8>,
+    /// Degrees clockwise from "towards the top": 90 runs left to right, 180 top to bottom.
+    angle: f32,
+}
+
+fn parse_gradient(s: &str) -> Result<Gradient, String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    if parts.len() != 3 {
+        return Err(format!(
+            "invalid gradient '{}', expected RRGGBB,RRGGBB,angle",
+            s
+        ));
     }
-    let e = best.ok_or_else(|| anyhow!("No entries"))?;
-    let w_decl = if e.width == 0 { 256 } else { e.width as u32 };
-    let h_decl = if e.height == 0 { 256 } else { e.height as u32 };
-    if debug {
-        eprintln!(
-            "[debug] chosen entry decl={}x{} bpp={} off={} bytes={} ",
-            w_decl, h_decl, e.bitcount, e.image_offset, e.bytes_in_res
-        );
+    let angle = parts[2]
+        .trim_end_matches("deg")
+        .parse::<f32>()
+        .map_err(|_| format!("invalid gradient angle '{}'", parts[2]))?;
+    Ok(Gradient {
+        from: parse_hex_color(parts[0])?,
+        to: parse_hex_color(parts[1])?,
+        angle,
+    })
+}
+
+// Fill a size x size square with the gradient. As in CSS, the gradient line passes through
+// the center and is just long enough for the corners to get the pure end colors.
+fn render_gradient(size: u32, gradient: &Gradient) -> RgbaImage {
+    let (sin, cos) = gradient.angle.to_radians().sin_cos();
+    let (dx, dy) = (sin, -cos);
+    let half = size as f32 / 2.0;
+    let length = (size as f32 * sin.abs() + size as f32 * cos.abs()).max(1.0);
+    RgbaImage::from_fn(size, size, |x, y| {
+        let (px, py) = (x as f32 + 0.5 - half, y as f32 + 0.5 - half);
+        let t = ((px * dx + py * dy) / length + 0.5).clamp(0.0, 1.0);
+        let mix = |c: usize| {
+            (gradient.from[c] as f32 * (1.0 - t) + gradient.to[c] as f32 * t).round() as u8
+        };
+        Rgba([mix(0), mix(1), mix(2), mix(3)])
+    })
+}
+
+// Render a blurred, offset copy of the artwork's alpha beneath it. Offsets are
+// clamped to a quarter of the icon size so the shadow never leaves the square.
+fn apply_shadow(canvas: &RgbaImage, shadow: &Shadow) -> RgbaImage {
+    let size = canvas.width();
+    let scale = size as f32 / 256.0;
+    let limit = (size / 4) as i64;
+    let dx = ((shadow.dx * scale).round() as i64).clamp(-limit, limit);
+    let dy = ((shadow.dy * scale).round() as i64).clamp(-limit, limit);
+    let [r, g, b, a] = shadow.color.0;
+    // Transparent pixels carry the shadow color too so blurring doesn't darken the edges.
+    let mut silhouette = RgbaImage::from_pixel(size, size, Rgba([r, g, b, 0]));
+    for (x, y, p) in canvas.enumerate_pixels() {
+        let sx = x as i64 + dx;
+        let sy = y as i64 + dy;
+        if sx < 0 || sy < 0 || sx >= size as i64 || sy >= canvas.height() as i64 {
+            continue;
+        }
+        let alpha = (p[3] as u16 * a as u16 / 255) as u8;
+        silhouette.put_pixel(sx as u32, sy as u32, Rgba([r, g, b, alpha]));
     }
-    f.seek(SeekFrom::Start(e.image_offset as u64))?;
-    let mut blob = vec![0u8; e.bytes_in_res as usize];
-    f.read_exact(&mut blob)?;
-    ensure_dir(out_dir)?;
-    const PNG_SIG: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
-    if blob.len() >= 8 && &blob[..8] == PNG_SIG {
-        // png
-        let img = image::load_from_memory(&blob).with_context(|| "decode PNG")?;
-        let rgba = img.to_rgba8();
-        let (w, h) = (rgba.width(), rgba.height());
-        let out_path = out_dir.join(format!("{}x{}.png", w, h));
-        rgba.save(&out_path)?;
-        if debug {
-            eprintln!("[debug] wrote {}", out_path.display());
+    let sigma = shadow.blur * scale;
+    let mut out = if sigma > 0.0 {
+        imageops::blur(&silhouette, sigma)
+    } else {
+        silhouette
+    };
+    imageops::overlay(&mut out, canvas, 0, 0);
+    out
+}
+
+fn resized_rgba(base: &DynamicImage, size: u32, opts: &RenderOptions) -> RgbaImage {
+    let filter = opts.artwork_filter(base, size);
+    if opts.nonsquare {
+        // Shadow, backplate, letterbox and mask all assume a square canvas; the CLI
+        // rejects them together with --allow-nonsquare.
+        let mut rect = resize_fit(base, size, filter);
+        if opts.alpha_bleed {
+            bleed_edge_colors(&mut rect);
         }
-        return Ok(());
+        return rect;
     }
-    // DIB path minimal support (32bpp + 8bpp indexed)
-    if blob.len() < 40 {
-        bail!("Unsupported blob format");
+    let canvas = if opts.contain {
+        resize_contain(base, size, filter)
+    } else {
+        resize_cover(base, size, filter)
+    };
+    let canvas = match &opts.shadow {
+        Some(shadow) => apply_shadow(&canvas, shadow),
+        None => canvas,
+    };
+    let canvas = match (opts.letterbox, opts.contain) {
+        (Some(color), true) => {
+            let mut plate = letterbox_plate(size, contain_rect(base, size), color);
+            imageops::overlay(&mut plate, &canvas, 0, 0);
+            plate
+        }
+        _ => canvas,
+    };
+    let mut canvas = match &opts.background_image {
+        Some(background) => {
+            let mut plate = resize_cover(background, size, opts.filter_for(size));
+            imageops::overlay(&mut plate, &canvas, 0, 0);
+            plate
+        }
+        None => canvas,
+    };
+    if let Some(gradient) = &opts.background_gradient {
+        let mut plate = render_gradient(size, gradient);
+        imageops::overlay(&mut plate, &canvas, 0, 0);
+        canvas = plate;
     }
-    let header_size = u32::from_le_bytes(blob[0..4].try_into().unwrap()) as usize;
-    if header_size < 40 {
-        bail!("Unsupported DIB header");
+    if let Some(label) = &opts.label {
+        apply_label(&mut canvas, label);
     }
-    let dib_w = i32::from_le_bytes(blob[4..8].try_into().unwrap()) as u32;
-    let dib_h_total = i32::from_le_bytes(blob[8..12].try_into().unwrap());
-    if dib_h_total <= 0 {
-        bail!("Invalid DIB height");
+    if let Some(mask) = &opts.mask {
+        apply_mask(&mut canvas, mask, opts.filter_for(size));
     }
-    let dib_h = (dib_h_total as u32) / 2;
-    let bpp = u16::from_le_bytes(blob[14..16].try_into().unwrap());
-    let compression = u32::from_le_bytes(blob[16..20].try_into().unwrap());
-    let clr_used = u32::from_le_bytes(blob[32..36].try_into().unwrap());
-    if compression != 0 {
-        bail!("Compressed DIB unsupported");
+    if opts.alpha_bleed {
+        bleed_edge_colors(&mut canvas);
     }
-    if bpp == 32 {
-        let expected = (dib_w * dib_h) as usize * 4;
-        if blob.len() < header_size + expected {
-            bail!("Truncated 32bpp data");
-        }
-        let data = &blob[header_size..header_size + expected];
-        let mut rgba = RgbaImage::new(dib_w, dib_h);
-        for y in 0..dib_h {
-            let src_row = (dib_h - 1 - y) as usize;
-            for x in 0..dib_w {
-                let i = (src_row * dib_w as usize + x as usize) * 4;
-                let b = data[i];
-                let g = data[i + 1];
-                let r = data[i + 2];
-                let a = data[i + 3];
-                rgba.put_pixel(x, y, Rgba([r, g, b, a]));
-            }
-        }
-        let out_path = out_dir.join(format!("{}x{}.png", dib_w, dib_h));
-        rgba.save(&out_path)?;
-        if debug {
-            eprintln!("[debug] wrote {} (DIB32)", out_path.display());
+    canvas
+}
+
+// Give fully transparent pixels the color of the nearest visible ones, one ring at a time
+// (each the average of its already-colored 8-neighbours). Alpha is left alone, so the
+// image looks the same, but filtering that mixes in hidden RGB (bilinear GPU scaling, or
+// our own straight-alpha resize) no longer pulls edges toward black.
+fn bleed_edge_colors(img: &mut RgbaImage) {
+    let (w, h) = img.dimensions();
+    let at = |x: u32, y: u32| (y * w + x) as usize;
+    let mut colored: Vec<bool> = img.pixels().map(|p| p[3] > 0).collect();
+    let neighbours = |x: u32, y: u32| {
+        (y.saturating_sub(1)..(y + 2).min(h))
+            .flat_map(move |ny| (x.saturating_sub(1)..(x + 2).min(w)).map(move |nx| (nx, ny)))
+            .filter(move |&n| n != (x, y))
+    };
+    let mut queued = colored.clone();
+    let mut ring: Vec<(u32, u32)> = Vec::new();
+    for (x, y, _) in img.enumerate_pixels() {
+        if !colored[at(x, y)] && neighbours(x, y).any(|(nx, ny)| colored[at(nx, ny)]) {
+            ring.push((x, y));
+            queued[at(x, y)] = true;
         }
-        return Ok(());
     }
-    if bpp == 8 {
-        let palette_len = if clr_used > 0 { clr_used as usize } else { 256 };
-        let palette_bytes = palette_len * 4;
-        if blob.len() < header_size + palette_bytes {
-            bail!("Truncated palette");
-        }
-        let palette = &blob[header_size..header_size + palette_bytes];
-        let row_stride = ((dib_w * bpp as u32 + 31) / 32) * 4;
-        let pixel_array_size = (row_stride * dib_h) as usize;
-        let pixel_offset = header_size + palette_bytes;
-        if blob.len() < pixel_offset + pixel_array_size {
-            bail!("Truncated pixel array");
-        }
-        let pixels = &blob[pixel_offset..pixel_offset + pixel_array_size];
-        let mask_stride = ((dib_w + 31) / 32) * 4;
-        let mask_offset = pixel_offset + pixel_array_size;
-        let mask = if blob.len() >= mask_offset + (mask_stride * dib_h) as usize {
-            Some(&blob[mask_offset..mask_offset + (mask_stride * dib_h) as usize])
-        } else {
-            None
-        };
-        let mut rgba = RgbaImage::new(dib_w, dib_h);
-        for y in 0..dib_h {
-            let src_row = (dib_h - 1 - y) as usize;
-            let row_start = src_row * row_stride as usize;
-            for x in 0..dib_w {
-                let idx8 = pixels[row_start + x as usize] as usize;
-                let base = (idx8.min(palette_len - 1)) * 4;
-                let b = palette[base];
-                let g = palette[base + 1];
-                let r = palette[base + 2];
-                rgba.put_pixel(x, y, Rgba([r, g, b, 0xFF]));
-            }
-        }
-        if let Some(mask_bytes) = mask {
-            for y in 0..dib_h {
-                let src_row = (dib_h - 1 - y) as usize;
-                let row_off = src_row * mask_stride as usize;
-                for x in 0..dib_w {
-                    let byte_index = row_off + (x / 8) as usize;
-                    let bit = 7 - (x % 8);
-                    if byte_index < mask_bytes.len() && ((mask_bytes[byte_index] >> bit) & 1) == 1 {
-                        rgba.get_pixel_mut(x, y).0[3] = 0;
+    while !ring.is_empty() {
+        let fills: Vec<[u8; 3]> = ring
+            .iter()
+            .map(|&(x, y)| {
+                let (mut sum, mut n) = ([0u32; 3], 0);
+                for (nx, ny) in neighbours(x, y).filter(|&(nx, ny)| colored[at(nx, ny)]) {
+                    let p = img.get_pixel(nx, ny);
+                    for c in 0..3 {
+                        sum[c] += p[c] as u32;
                     }
+                    n += 1;
                 }
-            }
+                sum.map(|v| ((v + n / 2) / n) as u8)
+            })
+            .collect();
+        for (&(x, y), rgb) in ring.iter().zip(fills) {
+            let p = img.get_pixel_mut(x, y);
+            p.0 = [rgb[0], rgb[1], rgb[2], p[3]];
+            colored[at(x, y)] = true;
         }
-        let out_path = out_dir.join(format!("{}x{}.png", dib_w, dib_h));
-        rgba.save(&out_path)?;
-        if debug {
-            eprintln!("[debug] wrote {} (DIB8)", out_path.display());
+        let mut next = Vec::new();
+        for &(x, y) in &ring {
+            for (nx, ny) in neighbours(x, y) {
+                if !queued[at(nx, ny)] {
+                    queued[at(nx, ny)] = true;
+                    next.push((nx, ny));
+                }
+            }
         }
-        return Ok(());
+        ring = next;
     }
-    bail!("Unsupported DIB bpp={}", bpp)
 }
 
-// Attempt to manually decode a PNG-backed ICO entry when ico crate fails (e.g., indexed color PNG)
-// Legacy stub kept for compatibility (no longer used)
-#[allow(dead_code)]
-fn try_decode_entry_png(
-    _path: &Path,
-    _entry: &ico::IconDirEntry,
-    _debug: bool,
-) -> Result<Option<ico::IconImage>> {
-    Ok(None)
+// Scale the finished icon's alpha by the mask, cover-fitted to the same size. White opaque
+// mask pixels keep the artwork; black or transparent ones remove it.
+fn apply_mask(canvas: &mut RgbaImage, mask: &DynamicImage, filter: ResizeFilter) {
+    let mask =
+        DynamicImage::ImageRgba8(resize_cover(mask, canvas.width(), filter)).to_luma_alpha8();
+    for (p, m) in canvas.pixels_mut().zip(mask.pixels()) {
+        let [luma, alpha] = m.0;
+        let coverage = luma as u32 * alpha as u32;
+        p[3] = ((p[3] as u32 * coverage + 255 * 255 / 2) / (255 * 255)) as u8;
+    }
 }
 
-// Removed multi-image write helper; simplified single largest extraction.
+// A plate with the color in the padding only and transparent inside the artwork
+// rectangle, laid beneath the (shadowed) artwork so the shadow falls on the bars and
+// transparency inside the artwork itself is left as is.
+fn letterbox_plate(size: u32, rect: (u32, u32, u32, u32), color: Rgba<u8>) -> RgbaImage {
+    let (x0, y0, w, h) = rect;
+    RgbaImage::from_fn(size, size, |x, y| {
+        if x < x0 || y < y0 || x >= x0 + w || y >= y0 + h {
+            color
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    })
+}
 
-fn extract_icns(path: &Path, out_dir: &Path, debug: bool) -> Result<()> {
-    use icns::{IconFamily, IconType};
-    let mut data = Vec::new();
-    File::open(path)?.read_to_end(&mut data)?;
-    let family = IconFamily::read(data.as_slice()).with_context(|| "read icns")?;
-    let mut best_img: Option<(u32, u32, icns::Image)> = None;
-    let sizes = [16u32, 32, 64, 128, 256, 512, 1024];
-    for s in sizes {
-        if let Some(t) = IconType::from_pixel_size(s, s) {
-            if let Ok(img) = family.get_icon_with_type(t) {
-                let w = img.width();
-                let h = img.height();
-                if debug {
-                    eprintln!("[debug] candidate {}x{}", w, h);
-                }
-                let area = w * h;
-                if best_img.as_ref().map(|(bw, bh, _)| bw * bh).unwrap_or(0) < area {
-                    best_img = Some((w, h, img));
-                }
+// Composite onto an opaque color so no transparency remains.
+fn flatten(img: &RgbaImage, background: Rgba<u8>) -> RgbaImage {
+    let [r, g, b, _] = background.0;
+    let mut out = RgbaImage::from_pixel(img.width(), img.height(), Rgba([r, g, b, 0xFF]));
+    imageops::overlay(&mut out, img, 0, 0);
+    out
+}
+
+fn load_image(path: &Path) -> Result<DynamicImage> {
+    load_image_frame(path, None)
+}
+
+// `frame` selects a frame of an animated GIF/PNG or a page of a multi-page TIFF; any
+// other input counts as a single frame.
+fn load_image_frame(path: &Path, frame: Option<usize>) -> Result<DynamicImage> {
+    let img = if let Some(index) = frame {
+        decode_frame(path, index)
+    } else if input_extension(path) == "jxl" {
+        decode_jxl(&read_input(path)?)
+    } else if input_url(path).is_some() {
+        let data = read_input(path)?;
+        image::load_from_memory(&data).map_err(Into::into)
+    } else {
+        image::open(path).map_err(Into::into)
+    }
+    .with_context(|| format!("Open image {}", path.display()))?;
+    // Every fit below divides by the source dimensions.
+    if img.width() == 0 || img.height() == 0 {
+        bail!(IconError::Malformed(format!(
+            "{} is {}x{}; images must be at least 1x1",
+            path.display(),
+            img.width(),
+            img.height()
+        )));
+    }
+    Ok(img)
+}
+
+fn decode_frame(path: &Path, index: usize) -> Result<DynamicImage> {
+    use image::AnimationDecoder;
+    use image::codecs::png::PngDecoder;
+    match input_extension(path).as_str() {
+        "gif" => decode_gif_frame(&read_input(path)?, index),
+        "tif" | "tiff" => decode_tiff_page(&read_input(path)?, index),
+        "png" | "apng" => {
+            let data = read_input(path)?;
+            let decoder = PngDecoder::new(Cursor::new(&data[..]))?;
+            if decoder.is_apng()? {
+                let rgba = nth_frame(decoder.apng()?.into_frames(), index)?;
+                Ok(DynamicImage::ImageRgba8(rgba))
+            } else {
+                single_frame(path, index)
             }
         }
+        _ => single_frame(path, index),
     }
-    let (w, h, img) = best_img.ok_or_else(|| anyhow!("No images in ICNS"))?;
-    ensure_dir(out_dir)?;
-    let out_path = out_dir.join(format!("{}x{}.png", w, h));
-    image::RgbaImage::from_raw(w, h, img.data().to_vec())
-        .ok_or_else(|| anyhow!("raw to image"))?
-        .save(&out_path)?;
-    if debug {
-        eprintln!("[debug] wrote {}", out_path.display());
+}
+
+fn single_frame(path: &Path, index: usize) -> Result<DynamicImage> {
+    if index > 0 {
+        bail!("frame {} is out of range: the input has 1 frame", index);
     }
-    Ok(())
+    load_image(path)
+}
+
+fn nth_frame(frames: image::Frames<'_>, index: usize) -> Result<RgbaImage> {
+    let mut count = 0;
+    for frame in frames {
+        let frame = frame.with_context(|| format!("decode frame {}", count))?;
+        if count == index {
+            return Ok(frame.into_buffer());
+        }
+        count += 1;
+    }
+    bail!(
+        "frame {} is out of range: the input has {} frame{}",
+        index,
+        count,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+// Frames come out composited onto the full canvas, as a viewer would show them.
+#[cfg(feature = "gif")]
+fn decode_gif_frame(data: &[u8], index: usize) -> Result<DynamicImage> {
+    use image::AnimationDecoder;
+    let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(data))?;
+    Ok(DynamicImage::ImageRgba8(nth_frame(
+        decoder.into_frames(),
+        index,
+    )?))
+}
+
+#[cfg(not(feature = "gif"))]
+fn decode_gif_frame(_data: &[u8], _index: usize) -> Result<DynamicImage> {
+    bail!(IconError::Unsupported(
+        "Cannot decode GIF: built without the `gif` feature".into()
+    ))
+}
+
+// image's TIFF decoder only reads the first page, so pages are walked with `tiff` itself.
+#[cfg(feature = "tiff")]
+fn decode_tiff_page(data: &[u8], index: usize) -> Result<DynamicImage> {
+    use tiff::ColorType;
+    use tiff::decoder::{Decoder, DecodingResult};
+    let mut decoder = Decoder::new(Cursor::new(data))?;
+    let mut pages = 1;
+    while pages <= index {
+        if !decoder.more_images() {
+            bail!(
+                "frame {} is out of range: the input has {} page{}",
+                index,
+                pages,
+                if pages == 1 { "" } else { "s" }
+            );
+        }
+        decoder.next_image()?;
+        pages += 1;
+    }
+    let (w, h) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    let DecodingResult::U8(buf) = decoder.read_image()? else {
+        bail!(IconError::Unsupported(format!(
+            "TIFF page {} is not 8 bits per sample",
+            index
+        )));
+    };
+    let img = match color {
+        ColorType::Gray(8) => image::GrayImage::from_raw(w, h, buf).map(DynamicImage::ImageLuma8),
+        ColorType::GrayA(8) => {
+            image::GrayAlphaImage::from_raw(w, h, buf).map(DynamicImage::ImageLumaA8)
+        }
+        ColorType::RGB(8) => image::RgbImage::from_raw(w, h, buf).map(DynamicImage::ImageRgb8),
+        ColorType::RGBA(8) => RgbaImage::from_raw(w, h, buf).map(DynamicImage::ImageRgba8),
+        other => bail!(IconError::Unsupported(format!(
+            "TIFF page {} has color type {:?}",
+            index, other
+        ))),
+    };
+    img.ok_or_else(|| IconError::Malformed(format!("TIFF page {} is truncated", index)).into())
+}
+
+#[cfg(not(feature = "tiff"))]
+fn decode_tiff_page(_data: &[u8], _index: usize) -> Result<DynamicImage> {
+    bail!(IconError::Unsupported(
+        "Cannot pick a TIFF page: built without the `tiff` feature".into()
+    ))
+}
+
+#[cfg(feature = "jxl")]
+fn decode_jxl(data: &[u8]) -> Result<DynamicImage> {
+    let decoder = jxl_oxide::integration::JxlDecoder::new(data)?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
+
+#[cfg(not(feature = "jxl"))]
+fn decode_jxl(_data: &[u8]) -> Result<DynamicImage> {
+    bail!(IconError::Unsupported(
+        "Cannot decode JPEG XL: built without the `jxl` feature".into()
+    ))
+}
+
+// Upper bound for a fetched input, so a wrong URL can't fill memory.
+#[cfg(feature = "network")]
+const MAX_DOWNLOAD_BYTES: u64 = 64 << 20;
+
+// An input given as an http(s) URL rather than a file path.
+fn input_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+// Read an input file, or fetch it when it is a URL.
+fn read_input(path: &Path) -> Result<Vec<u8>> {
+    match input_url(path) {
+        Some(url) => fetch_url(url),
+        None => fs::read(path).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+// Lowercase extension of an input; for URLs the query string and fragment are ignored.
+fn input_extension(path: &Path) -> String {
+    let path = match input_url(path) {
+        Some(url) => Path::new(url.split(['?', '#']).next().unwrap_or(url)),
+        None => path,
+    };
+    path.extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+#[cfg(feature = "network")]
+fn fetch_url(url: &str) -> Result<Vec<u8>> {
+    let response = ureq::get(url)
+        .call()
+        .with_context(|| format!("fetch {}", url))?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_DOWNLOAD_BYTES + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("fetch {}", url))?;
+    if data.len() as u64 > MAX_DOWNLOAD_BYTES {
+        bail!("{} is larger than {} MiB", url, MAX_DOWNLOAD_BYTES >> 20);
+    }
+    Ok(data)
+}
+
+#[cfg(not(feature = "network"))]
+fn fetch_url(url: &str) -> Result<Vec<u8>> {
+    bail!(IconError::Unsupported(format!(
+        "Cannot fetch {}: built without the `network` feature",
+        url
+    )))
+}
+
+/// zlib effort for the PNGs this tool encodes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum PngCompression {
+    /// Quickest to write, largest files
+    Fast,
+    #[default]
+    Default,
+    /// Smallest files, slowest to write
+    Best,
+}
+
+// Set once from --png-compression before any command runs.
+static PNG_COMPRESSION: std::sync::Mutex<PngCompression> =
+    std::sync::Mutex::new(PngCompression::Default);
+
+fn png_compression() -> PngCompression {
+    *PNG_COMPRESSION.lock().unwrap()
+}
+
+fn png_encoder<W: Write>(w: W) -> image::codecs::png::PngEncoder<W> {
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+    let compression = match png_compression() {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+    PngEncoder::new_with_quality(w, compression, FilterType::Adaptive)
+}
+
+// Encode RGBA as PNG, embedding an ICC profile (iCCP chunk) when given.
+fn encode_png(rgba: &RgbaImage, icc_profile: Option<&[u8]>) -> Result<Vec<u8>> {
+    use image::ImageEncoder;
+    let mut buf = Vec::new();
+    let mut encoder = png_encoder(&mut buf);
+    if let Some(icc) = icc_profile {
+        encoder.set_icc_profile(icc.to_vec())?;
+    }
+    encoder.write_image(
+        rgba.as_raw(),
+        rgba.width(),
+        rgba.height(),
+        image::ExtendedColorType::Rgba8,
+    )?;
+    Ok(buf)
+}
+
+// Like `encode_png`, but opaque images that are all gray or use at most 256 colors are
+// stored as 8-bit grayscale or palette PNGs. Anything else stays RGBA.
+fn encode_png_reduced(rgba: &RgbaImage, icc_profile: Option<&[u8]>) -> Result<Vec<u8>> {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    if rgba.pixels().any(|p| p[3] != 0xFF) {
+        return encode_png(rgba, icc_profile);
+    }
+    let mut gray = true;
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity((rgba.width() * rgba.height()) as usize);
+    for p in rgba.pixels() {
+        let [r, g, b, _] = p.0;
+        gray &= r == g && g == b;
+        if palette.len() <= 256 {
+            let next = palette.len();
+            let index = *lookup.entry([r, g, b]).or_insert_with(|| {
+                palette.push([r, g, b]);
+                next as u8
+            });
+            indices.push(index);
+        }
+    }
+    if gray {
+        use image::ImageEncoder;
+        let luma: Vec<u8> = rgba.pixels().map(|p| p[0]).collect();
+        let mut buf = Vec::new();
+        let mut encoder = png_encoder(&mut buf);
+        if let Some(icc) = icc_profile {
+            encoder.set_icc_profile(icc.to_vec())?;
+        }
+        encoder.write_image(
+            &luma,
+            rgba.width(),
+            rgba.height(),
+            image::ExtendedColorType::L8,
+        )?;
+        return Ok(buf);
+    }
+    if palette.len() > 256 {
+        return encode_png(rgba, icc_profile);
+    }
+    let mut info = png::Info::with_size(rgba.width(), rgba.height());
+    info.color_type = png::ColorType::Indexed;
+    info.bit_depth = png::BitDepth::Eight;
+    info.palette = Some(Cow::Owned(palette.concat()));
+    info.icc_profile = icc_profile.map(Cow::Borrowed);
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::with_info(&mut buf, info)?;
+    encoder.set_compression(match png_compression() {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+    Ok(buf)
+}
+
+// Quality of JPEG output; high enough that icon edges don't ring visibly.
+const JPEG_QUALITY: u8 = 90;
+
+// Baseline JPEG, for `extract` to a .jpg path. JPEG has no alpha, so the image is first
+// composited over `matte`; dropping the alpha instead would turn transparency black.
+fn encode_jpeg(rgba: &RgbaImage, matte: Rgba<u8>) -> Result<Vec<u8>> {
+    let rgb = DynamicImage::ImageRgba8(flatten(rgba, matte)).to_rgb8();
+    let mut buf = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY).encode(
+        rgb.as_raw(),
+        rgb.width(),
+        rgb.height(),
+        image::ExtendedColorType::Rgb8,
+    )?;
+    Ok(buf)
+}
+
+// Lossless WebP with alpha, for `extract` to a .webp path.
+fn encode_webp(rgba: &RgbaImage) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    image::codecs::webp::WebPEncoder::new_lossless(&mut buf).encode(
+        rgba.as_raw(),
+        rgba.width(),
+        rgba.height(),
+        image::ExtendedColorType::Rgba8,
+    )?;
+    Ok(buf)
+}
+
+// 32-bit BMP with alpha, for `extract` to a .bmp path.
+fn encode_bmp(rgba: &RgbaImage) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    image::codecs::bmp::BmpEncoder::new(&mut buf).encode(
+        rgba.as_raw(),
+        rgba.width(),
+        rgba.height(),
+        image::ExtendedColorType::Rgba8,
+    )?;
+    Ok(buf)
+}
+
+fn save_png(rgba: &RgbaImage, out_path: &Path, icc_profile: Option<&[u8]>) -> Result<()> {
+    let bytes = encode_png(rgba, icc_profile)?;
+    fs::write(out_path, bytes).with_context(|| format!("save {}", out_path.display()))
+}
+
+// Per-size progress on stderr, hidden under --quiet or when stdout isn't a terminal.
+fn progress_bar(len: usize) -> indicatif::ProgressBar {
+    use std::io::IsTerminal;
+    if quiet() || !std::io::stdout().is_terminal() {
+        return indicatif::ProgressBar::hidden();
+    }
+    let bar = indicatif::ProgressBar::new(len as u64);
+    bar.set_style(
+        indicatif::ProgressStyle::with_template("{bar:30} {pos}/{len} {msg}")
+            .unwrap_or_else(|_| indicatif::ProgressStyle::default_bar()),
+    );
+    bar
+}
+
+// Worker threads for per-size rendering, set once from --threads; 0 means one per CPU.
+static THREADS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn worker_count() -> usize {
+    match THREADS.load(std::sync::atomic::Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+// Stage durations for `build --time`, in the order they finished; `None` unless enabled.
+static TIMINGS: std::sync::Mutex<Option<Vec<(String, Duration)>>> = std::sync::Mutex::new(None);
+
+fn record_time(stage: impl FnOnce() -> String, took: Duration) {
+    if let Some(timings) = TIMINGS.lock().unwrap().as_mut() {
+        timings.push((stage(), took));
+    }
+}
+
+// Run `f` and record how long it took under `stage`.
+fn timed<T>(stage: impl FnOnce() -> String, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let out = f();
+    record_time(stage, start.elapsed());
+    out
+}
+
+fn print_timings(total: Duration) {
+    let Some(timings) = TIMINGS.lock().unwrap().take() else {
+        return;
+    };
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    diag!("Timings:");
+    for (stage, took) in &timings {
+        diag!("  {:<16} {:>9.2} ms", stage, ms(*took));
+    }
+    diag!("  {:<16} {:>9.2} ms", "total", ms(total));
+}
+
+/// One stored image of a built icon, for `build --report`.
+#[derive(Debug)]
+struct ReportEntry {
+    width: u32,
+    height: u32,
+    /// `png` or `bmp` for ICO entries, the element type for ICNS.
+    encoding: String,
+    /// Declared bit depth; ICNS elements have none.
+    bitcount: Option<u16>,
+    bytes: usize,
+}
+
+// Images of the icon just written, for `build --report`; `None` unless enabled.
+static REPORT: std::sync::Mutex<Option<Vec<ReportEntry>>> = std::sync::Mutex::new(None);
+
+fn record_report(entries: impl FnOnce() -> Vec<ReportEntry>) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        report.extend(entries());
+    }
+}
+
+// Summarize a finished build: the source, the fit, each stored image with the filter its
+// size was scaled with, and the file size. Printed as a table and/or written as JSON.
+fn print_report(
+    source: (&Path, u32, u32),
+    out: &Path,
+    img: &DynamicImage,
+    opts: &RenderOptions,
+    print: bool,
+    json: Option<&Path>,
+) -> Result<()> {
+    let Some(entries) = REPORT.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let total = fs::metadata(out)
+        .with_context(|| format!("stat {}", out.display()))?
+        .len();
+    let fit = if opts.contain { "contain" } else { "cover" };
+    let filter = |size: u32| {
+        let filter = opts.artwork_filter(img, size).to_possible_value().unwrap();
+        filter.get_name().to_string()
+    };
+    if print {
+        status!("Report for {}:", out.display());
+        status!(
+            "  source {} ({}x{}), fit {}",
+            source.0.display(),
+            source.1,
+            source.2,
+            fit
+        );
+        for e in &entries {
+            let depth = e.bitcount.map(|b| format!("{}bpp", b)).unwrap_or_default();
+            status!(
+                "  {:>9} {:<5} {:>6} {:>9} bytes  {}",
+                format!("{}x{}", e.width, e.height),
+                e.encoding,
+                depth,
+                e.bytes,
+                filter(e.width)
+            );
+        }
+        status!("  total {} images, {} bytes", entries.len(), total);
+    }
+    if let Some(path) = json {
+        let images: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|e| {
+                serde_json::json!({
+                    "width": e.width,
+                    "height": e.height,
+                    "encoding": e.encoding,
+                    "bitcount": e.bitcount,
+                    "bytes": e.bytes,
+                    "filter": filter(e.width),
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "output": out.display().to_string(),
+            "bytes": total,
+            "source": {
+                "path": source.0.display().to_string(),
+                "width": source.1,
+                "height": source.2,
+            },
+            "fit": fit,
+            "images": images,
+        });
+        fs::write(path, serde_json::to_string_pretty(&report)? + "\n")
+            .with_context(|| format!("write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// Render every size on a scoped pool of `worker_count()` threads. Results come back in the
+// order of `sizes`, so the output never depends on scheduling; one worker runs inline.
+fn render_sizes(
+    source: &DynamicImage,
+    sizes: &[u32],
+    opts: &RenderOptions,
+    progress: &indicatif::ProgressBar,
+) -> Vec<RgbaImage> {
+    let render = |s: u32| {
+        let start = Instant::now();
+        let rgba = resized_rgba(opts.source_for(s).unwrap_or(source), s, opts);
+        progress.inc(1);
+        (rgba, start.elapsed())
+    };
+    let workers = worker_count().min(sizes.len());
+    let rendered: Vec<(RgbaImage, Duration)> = if workers <= 1 {
+        sizes.iter().map(|&s| render(s)).collect()
+    } else {
+        render_parallel(sizes, workers, render)
+    };
+    // Recorded afterwards so the timing report lists sizes in order.
+    sizes
+        .iter()
+        .zip(rendered)
+        .map(|(&s, (rgba, took))| {
+            record_time(|| format!("resize {}px", s), took);
+            rgba
+        })
+        .collect()
+}
+
+// Work-stealing over `sizes` on `workers` scoped threads, results in input order.
+fn render_parallel<T: Send>(
+    sizes: &[u32],
+    workers: usize,
+    render: impl Fn(u32) -> T + Sync,
+) -> Vec<T> {
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let mut out: Vec<Option<T>> = (0..sizes.len()).map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some(&s) = sizes.get(i) else { break };
+                        done.push((i, render(s)));
+                    }
+                    done
+                })
+            })
+            .collect();
+        for handle in handles {
+            let done = handle
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            for (i, item) in done {
+                out[i] = Some(item);
+            }
+        }
+    });
+    out.into_iter().map(Option::unwrap).collect()
+}
+
+fn ensure_dir(path: &Path) -> Result<()> {
+    if path.exists() && !path.is_dir() {
+        bail!("{} exists and is not dir", path.display());
+    }
+    fs::create_dir_all(path).with_context(|| format!("create dir {}", path.display()))
+}
+
+// ============ Color profiles ============
+
+// `srgb` selects the built-in profile; anything else is read as an .icc file.
+fn load_icc_profile(spec: &str) -> Result<Vec<u8>> {
+    if spec.eq_ignore_ascii_case("srgb") {
+        return Ok(srgb_icc_profile());
+    }
+    let data = fs::read(spec).with_context(|| format!("read ICC profile {}", spec))?;
+    if data.len() < 128 || &data[36..40] != b"acsp" {
+        bail!(IconError::Malformed(format!(
+            "{} is not an ICC profile",
+            spec
+        )));
+    }
+    Ok(data)
+}
+
+// Minimal ICC v2 display profile for sRGB: D50-adapted primaries and the sRGB tone curve.
+fn srgb_icc_profile() -> Vec<u8> {
+    fn xyz(x: f64, y: f64, z: f64) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for v in [x, y, z] {
+            tag.extend(((v * 65536.0).round() as i32).to_be_bytes());
+        }
+        tag
+    }
+    let name = b"sRGB IEC61966-2.1\0";
+    let mut desc = b"desc\0\0\0\0".to_vec();
+    desc.extend((name.len() as u32).to_be_bytes());
+    desc.extend(name);
+    desc.extend([0u8; 4 + 4 + 2 + 1 + 67]); // empty Unicode and ScriptCode descriptions
+    let mut cprt = b"text\0\0\0\0".to_vec();
+    cprt.extend(b"No copyright, use freely\0");
+    let mut trc = b"curv\0\0\0\0".to_vec();
+    let points = 1024u32;
+    trc.extend(points.to_be_bytes());
+    for i in 0..points {
+        let v = i as f64 / (points - 1) as f64;
+        let linear = if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        };
+        trc.extend(((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    let blobs = [
+        desc,
+        cprt,
+        xyz(0.9642, 1.0, 0.8249),
+        xyz(0.4361, 0.2225, 0.0139),
+        xyz(0.3851, 0.7169, 0.0971),
+        xyz(0.1431, 0.0606, 0.7141),
+        trc,
+    ];
+    // The three channels share one tone curve.
+    let tags: [(&[u8; 4], usize); 9] = [
+        (b"desc", 0),
+        (b"cprt", 1),
+        (b"wtpt", 2),
+        (b"rXYZ", 3),
+        (b"gXYZ", 4),
+        (b"bXYZ", 5),
+        (b"rTRC", 6),
+        (b"gTRC", 6),
+        (b"bTRC", 6),
+    ];
+    let data_start = 128 + 4 + 12 * tags.len();
+    let mut offsets = Vec::new();
+    let mut data = Vec::new();
+    for blob in &blobs {
+        offsets.push(data_start + data.len());
+        data.extend(blob);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    let mut profile = Vec::with_capacity(data_start + data.len());
+    profile.extend(((data_start + data.len()) as u32).to_be_bytes());
+    profile.extend([0u8; 4]); // preferred CMM
+    profile.extend(0x0210_0000u32.to_be_bytes()); // version 2.1
+    profile.extend(b"mntrRGB XYZ ");
+    for v in [2000u16, 1, 1, 0, 0, 0] {
+        profile.extend(v.to_be_bytes());
+    }
+    profile.extend(b"acsp");
+    profile.extend([0u8; 4 + 4 + 4 + 4 + 8 + 4]); // platform, flags, device, attributes, intent
+    profile.extend(&xyz(0.9642, 1.0, 0.8249)[8..]); // PCS illuminant (D50)
+    profile.extend([0u8; 4 + 16 + 28]); // creator, profile ID, reserved
+    profile.extend((tags.len() as u32).to_be_bytes());
+    for (sig, blob) in tags {
+        profile.extend(sig);
+        profile.extend((offsets[blob] as u32).to_be_bytes());
+        profile.extend((blobs[blob].len() as u32).to_be_bytes());
+    }
+    profile.extend(data);
+    profile
+}
+
+// ICC profile carried in a PNG's iCCP chunk, if any.
+fn png_icc_profile(png: &[u8]) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+    let mut decoder = image::codecs::png::PngDecoder::new(std::io::Cursor::new(png)).ok()?;
+    decoder.icc_profile().ok().flatten()
+}
+
+// ============ ICO / ICNS build ============
+
+const ICO_SIZES: &[u32] = &[16, 24, 32, 48, 64, 128, 256];
+
+// Sizes Windows Explorer and the shell request from an .ico (at 100% scaling unless noted);
+// a missing one is scaled from a neighbour at display time, usually blurrily.
+const WINDOWS_COMPLETE_SIZES: &[u32] = &[
+    16,  // "Small icons", Details and List views, title bars, the notification area
+    24,  // 16px uses at 150% scaling, some list and tree views
+    32,  // desktop, taskbar, 16px uses at 200%
+    48,  // Explorer "Medium icons"
+    64,  // 32px uses at 200%, "Medium icons" at 125-150%
+    96,  // Explorer "Large icons", 48px uses at 200%
+    128, // "Large icons" at 125-150%
+    256, // Explorer "Extra large icons" and Tiles, Large icons at 200%+
+];
+
+// Pixel sizes emitted into an ICNS (retina variants share pixel sizes with 1x ones).
+fn icns_sizes() -> Vec<u32> {
+    use icns::IconType;
+    use std::collections::BTreeSet;
+    let all_sizes: &[u32] = &[16, 32, 64, 128, 256, 512, 1024, 32, 64, 256, 512, 1024];
+    let sizes: BTreeSet<u32> = all_sizes.iter().cloned().collect();
+    sizes
+        .into_iter()
+        .filter(|&s| IconType::from_pixel_size(s, s).is_some())
+        .collect()
+}
+
+fn target_sizes(format: TargetFormat) -> Vec<u32> {
+    match format {
+        TargetFormat::Ico => ICO_SIZES.to_vec(),
+        TargetFormat::Icns => icns_sizes(),
+    }
+}
+
+fn validate_size(format: TargetFormat, size: u32) -> Result<()> {
+    match format {
+        TargetFormat::Ico if !(1..=256).contains(&size) => {
+            bail!("{}px is not a valid ICO size (1-256)", size)
+        }
+        TargetFormat::Icns if icns::IconType::from_pixel_size(size, size).is_none() => {
+            bail!(
+                "{}px has no ICNS element type (use one of {:?})",
+                size,
+                icns_sizes()
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+// Print what a build would produce without touching the filesystem.
+fn print_build_plan(plan: &[(u32, &Path)], out: &Path) {
+    status!("would write {} ({} sizes)", out.display(), plan.len());
+    for (s, src) in plan {
+        status!("  {}x{} <- {}", s, s, src.display());
+    }
+}
+
+/// Default alpha cutoff for the 1-bit AND mask of BMP entries.
+const DEFAULT_ALPHA_THRESHOLD: u8 = 128;
+
+/// Container-level options for ICO output.
+#[derive(Clone, Debug)]
+struct IcoOptions {
+    /// Store 256px twice, as an uncompressed DIB and as PNG, for shells that only read one.
+    dual_256: bool,
+    /// ICC profile embedded into PNG-encoded entries.
+    icc_profile: Option<Vec<u8>>,
+    /// Pixels with alpha below this are transparent in the AND mask of BMP entries.
+    alpha_threshold: u8,
+    /// Encode sizes below this as BMP and the rest as PNG, instead of choosing per image.
+    png_min_size: Option<u32>,
+    /// Directory order of the sizes.
+    sort_sizes: SortOrder,
+    /// Also store each size as a 1bpp black-and-white DIB, white from this luminance up.
+    monochrome: Option<u8>,
+    /// Fail instead of writing when the assembled file is larger than this many bytes.
+    max_file_size: Option<u64>,
+}
+
+/// Order of sizes in a written ICO directory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum SortOrder {
+    /// Smallest first
+    #[default]
+    Asc,
+    /// Largest first, for consumers that take the first entry they can read
+    Desc,
+}
+
+impl Default for IcoOptions {
+    fn default() -> Self {
+        IcoOptions {
+            dual_256: false,
+            icc_profile: None,
+            alpha_threshold: DEFAULT_ALPHA_THRESHOLD,
+            png_min_size: None,
+            sort_sizes: SortOrder::Asc,
+            monochrome: None,
+            max_file_size: None,
+        }
+    }
+}
+
+/// Settings specific to writing .icns files.
+#[derive(Clone, Debug, Default)]
+struct IcnsOptions {
+    /// Leave out sizes that fail to encode instead of aborting the build.
+    strip_incomplete: bool,
+    /// Store 16 and 32px as `ic04`/`ic05` ARGB elements instead of PNG.
+    argb_small: bool,
+    /// Also store 16, 32, 48 and 128px as classic RGB elements with 8-bit mask elements.
+    legacy_masks: bool,
+    /// Fail instead of writing when the assembled file is larger than this many bytes.
+    max_file_size: Option<u64>,
+}
+
+/// One encoded ICO image: PNG or DIB payload plus its directory fields.
+#[derive(Clone, Debug)]
+struct IcoEntry {
+    width: u32,
+    height: u32,
+    bitcount: u16,
+    /// Palette-size byte read from an existing directory, written back unchanged; when
+    /// unset it is derived from `bitcount`.
+    colors: Option<u8>,
+    data: Vec<u8>,
+    /// Cursor hotspot; set on every entry of a .cur, on none of an .ico.
+    hotspot: Option<(u16, u16)>,
+}
+
+impl IcoEntry {
+    fn from_encoded(entry: &ico::IconDirEntry) -> Self {
+        IcoEntry {
+            width: entry.width(),
+            height: entry.height(),
+            bitcount: entry.bits_per_pixel(),
+            colors: None,
+            data: entry.data().to_vec(),
+            hotspot: None,
+        }
+    }
+
+    fn is_png(&self) -> bool {
+        self.data.starts_with(PNG_SIG)
+    }
+}
+
+// Canonical entry order for generated icons: size (ascending unless `order` is `Desc`), then
+// bit depth, then BMP before PNG. The sorts are stable, so the output never depends on how
+// entries were produced.
+fn normalize_ico_order(entries: &mut [IcoEntry], order: SortOrder) {
+    entries.sort_by_key(|e| (e.width, e.height, e.bitcount, e.is_png()));
+    if order == SortOrder::Desc {
+        entries.sort_by_key(|e| std::cmp::Reverse((e.width, e.height)));
+    }
+}
+
+// Regenerate the 1bpp AND mask that follows the color data of a DIB payload: a pixel is
+// masked out (bit set) when its source alpha is below `threshold`. Legacy renderers that
+// ignore the alpha channel draw edges from this mask alone.
+fn write_and_mask(dib: &mut [u8], rgba: &RgbaImage, threshold: u8) {
+    let header = u32::from_le_bytes(dib[0..4].try_into().unwrap()) as usize;
+    let bitcount = u16::from_le_bytes([dib[14], dib[15]]) as u32;
+    let colors_used = u32::from_le_bytes(dib[32..36].try_into().unwrap()) as usize;
+    let palette = match bitcount {
+        1..=8 if colors_used > 0 => colors_used * 4,
+        1..=8 => (1 << bitcount) * 4,
+        _ => 0,
+    };
+    let (w, h) = rgba.dimensions();
+    let color_stride = (w * bitcount).div_ceil(32) as usize * 4;
+    let mask_stride = w.div_ceil(32) as usize * 4;
+    let mask_at = header + palette + color_stride * h as usize;
+    let Some(mask) = dib.get_mut(mask_at..mask_at + mask_stride * h as usize) else {
+        return;
+    };
+    mask.fill(0);
+    // Rows are stored bottom-up.
+    for (y, row) in mask.chunks_mut(mask_stride).enumerate() {
+        let src_y = h - 1 - y as u32;
+        for x in 0..w {
+            if rgba.get_pixel(x, src_y)[3] < threshold {
+                row[x as usize / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+}
+
+// Encode a 1bpp black-and-white DIB entry for classic monochrome toolbar glyphs. A pixel
+// whose luminance is at least `luma_threshold` is white, otherwise black. The AND mask is
+// derived from alpha as in write_and_mask, and masked pixels are stored black.
+fn encode_mono_dib(rgba: &RgbaImage, luma_threshold: u8, alpha_threshold: u8) -> Vec<u8> {
+    let (w, h) = rgba.dimensions();
+    let stride = w.div_ceil(32) as usize * 4;
+    let mut dib = Vec::with_capacity(40 + 8 + stride * h as usize * 2);
+    dib.extend(40u32.to_le_bytes()); // BITMAPINFOHEADER
+    dib.extend(w.to_le_bytes());
+    dib.extend((h * 2).to_le_bytes()); // color rows + mask rows
+    dib.extend(1u16.to_le_bytes()); // planes
+    dib.extend(1u16.to_le_bytes()); // bit count
+    dib.extend(BI_RGB.to_le_bytes());
+    dib.extend(((stride * h as usize * 2) as u32).to_le_bytes());
+    dib.extend([0; 8]); // resolution
+    dib.extend(2u32.to_le_bytes()); // colors used
+    dib.extend(0u32.to_le_bytes()); // colors important
+    dib.extend([0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0]); // palette: black, white (BGRX)
+    let luma = |p: &Rgba<u8>| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64;
+    let mut color = vec![0u8; stride * h as usize];
+    // Rows are stored bottom-up.
+    for (y, row) in color.chunks_mut(stride).enumerate() {
+        let src_y = h - 1 - y as u32;
+        for x in 0..w {
+            let p = rgba.get_pixel(x, src_y);
+            if p[3] >= alpha_threshold && luma(p) >= luma_threshold as f64 {
+                row[x as usize / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    dib.extend(color);
+    dib.resize(dib.len() + stride * h as usize, 0);
+    write_and_mask(&mut dib, rgba, alpha_threshold);
+    dib
+}
+
+// Serialize an icon directory followed by the entry payloads, in the given order.
+// Entries with a hotspot make it a cursor directory.
+fn encode_ico(entries: &[IcoEntry]) -> Vec<u8> {
+    let cursor = entries.iter().any(|e| e.hotspot.is_some());
+    let mut buf = Vec::new();
+    buf.extend(0u16.to_le_bytes()); // reserved
+    buf.extend((if cursor { 2u16 } else { 1 }).to_le_bytes()); // type: icon or cursor
+    buf.extend((entries.len() as u16).to_le_bytes());
+    let mut offset = 6 + 16 * entries.len() as u32;
+    for e in entries {
+        // A zero byte means 256 (or larger for PNG payloads).
+        buf.push(if e.width >= 256 { 0 } else { e.width as u8 });
+        buf.push(if e.height >= 256 { 0 } else { e.height as u8 });
+        // Palette size; 0 for 8bpp (256 colors), true color, PNG and unknown (0) bit counts.
+        buf.push(e.colors.unwrap_or(match e.bitcount {
+            1 | 2 | 4 => 1 << e.bitcount,
+            _ => 0,
+        }));
+        buf.push(0); // reserved
+        // Cursors store the hotspot where icons store planes and bit count.
+        let (planes, bitcount) = if cursor {
+            e.hotspot.unwrap_or((0, 0))
+        } else {
+            (1, e.bitcount)
+        };
+        buf.extend(planes.to_le_bytes());
+        buf.extend(bitcount.to_le_bytes());
+        buf.extend((e.data.len() as u32).to_le_bytes());
+        buf.extend(offset.to_le_bytes());
+        offset += e.data.len() as u32;
+    }
+    for e in entries {
+        buf.extend(&e.data);
+    }
+    buf
+}
+
+fn build_ico(
+    source: &DynamicImage,
+    sizes: &[u32],
+    opts: &RenderOptions,
+    ico_opts: &IcoOptions,
+    out: &Path,
+) -> Result<()> {
+    let progress = progress_bar(sizes.len());
+    let rendered = render_sizes(source, sizes, opts, &progress);
+    progress.finish_and_clear();
+    write_ico(sizes, rendered, ico_opts, out)
+}
+
+// Encode already rendered images, one per entry of `sizes`, into an .ico.
+fn write_ico(
+    sizes: &[u32],
+    rendered: Vec<RgbaImage>,
+    ico_opts: &IcoOptions,
+    out: &Path,
+) -> Result<()> {
+    use ico::{IconDirEntry, IconImage};
+    let mut entries = Vec::new();
+    for (&s, rgba) in sizes.iter().zip(rendered) {
+        let start = Instant::now();
+        let (w, h) = rgba.dimensions();
+        let icon = IconImage::from_rgba_data(w, h, rgba.as_raw().clone());
+        let encoded = if s == 256 && ico_opts.dual_256 {
+            let bmp = IconDirEntry::encode_as_bmp(&icon)
+                .with_context(|| format!("encode {}px as BMP", s))?;
+            let png = IconDirEntry::encode_as_png(&icon)
+                .with_context(|| format!("encode {}px as PNG", s))?;
+            vec![bmp, png]
+        } else if let Some(min) = ico_opts.png_min_size {
+            let encoded = if s >= min {
+                IconDirEntry::encode_as_png(&icon)
+            } else {
+                IconDirEntry::encode_as_bmp(&icon)
+            };
+            vec![encoded.with_context(|| format!("encode {}px", s))?]
+        } else {
+            vec![IconDirEntry::encode(&icon).with_context(|| format!("encode {}px", s))?]
+        };
+        for encoded in &encoded {
+            let mut entry = IcoEntry::from_encoded(encoded);
+            // The ico crate always uses its own settings; re-encode for a profile or a
+            // non-default --png-compression.
+            let reencode =
+                ico_opts.icc_profile.is_some() || png_compression() != PngCompression::Default;
+            if entry.is_png() && reencode {
+                entry.data = encode_png(&rgba, ico_opts.icc_profile.as_deref())?;
+                entry.bitcount = 32;
+            }
+            if !entry.is_png() {
+                write_and_mask(&mut entry.data, &rgba, ico_opts.alpha_threshold);
+            }
+            entries.push(entry);
+        }
+        if let Some(luma) = ico_opts.monochrome {
+            entries.push(IcoEntry {
+                width: w,
+                height: h,
+                bitcount: 1,
+                colors: None,
+                data: encode_mono_dib(&rgba, luma, ico_opts.alpha_threshold),
+                hotspot: None,
+            });
+        }
+        record_time(|| format!("encode {}px", s), start.elapsed());
+    }
+    normalize_ico_order(&mut entries, ico_opts.sort_sizes);
+    let bytes = encode_ico(&entries);
+    if let Some(max) = ico_opts.max_file_size {
+        let parts = entries.iter().map(|e| {
+            let encoding = if e.is_png() { "png" } else { "bmp" };
+            let label = format!("{}x{} {} {}bpp", e.width, e.height, encoding, e.bitcount);
+            (label, e.data.len())
+        });
+        check_file_size(out, bytes.len(), max, parts.collect())?;
+    }
+    if let Some(parent) = out.parent() {
+        ensure_dir(parent)?;
+    }
+    timed(|| "write".into(), || fs::write(out, &bytes))
+        .with_context(|| format!("write ico {}", out.display()))?;
+    record_report(|| {
+        entries
+            .iter()
+            .map(|e| ReportEntry {
+                width: e.width,
+                height: e.height,
+                encoding: if e.is_png() { "png" } else { "bmp" }.into(),
+                bitcount: Some(e.bitcount),
+                bytes: e.data.len(),
+            })
+            .collect()
+    });
+    Ok(())
+}
+
+/// Entries listed when an output exceeds --max-file-size.
+const MAX_FILE_SIZE_LISTED: usize = 3;
+
+// Fail when the `len` bytes assembled for `out` exceed `max`, naming the entries that
+// contribute the most so it's clear which sizes to drop or shrink.
+fn check_file_size(
+    out: &Path,
+    len: usize,
+    max: u64,
+    mut parts: Vec<(String, usize)>,
+) -> Result<()> {
+    if len as u64 <= max {
+        return Ok(());
+    }
+    parts.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+    let largest: Vec<String> = parts
+        .iter()
+        .take(MAX_FILE_SIZE_LISTED)
+        .map(|(label, bytes)| format!("{} ({} bytes)", label, bytes))
+        .collect();
+    bail!(
+        "{} would be {} bytes, over the --max-file-size budget of {} KB ({} bytes); largest entries: {}",
+        out.display(),
+        len,
+        max / 1024,
+        max,
+        largest.join(", ")
+    )
+}
+
+// With `strip_incomplete`, a size that fails to encode is left out and reported instead of
+// aborting the build; it still fails when no size at all could be encoded. Returns the
+// sizes that were written.
+fn build_icns(
+    source: &DynamicImage,
+    sizes: &[u32],
+    opts: &RenderOptions,
+    icns_opts: &IcnsOptions,
+    out: &Path,
+) -> Result<Vec<u32>> {
+    let sizes: Vec<u32> = sizes
+        .iter()
+        .copied()
+        .filter(|&s| icns::IconType::from_pixel_size(s, s).is_some())
+        .collect();
+    let progress = progress_bar(sizes.len());
+    let rendered = render_sizes(source, &sizes, opts, &progress);
+    progress.finish_and_clear();
+    write_icns(&sizes, rendered, icns_opts, out)
+}
+
+// Encode already rendered images, one per entry of `sizes`, into an .icns. Every size
+// must have an element type.
+fn write_icns(
+    sizes: &[u32],
+    rendered: Vec<RgbaImage>,
+    icns_opts: &IcnsOptions,
+    out: &Path,
+) -> Result<Vec<u32>> {
+    use icns::{IconElement, IconFamily, IconType, Image, PixelFormat};
+    let mut family = IconFamily::new();
+    let mut dropped = Vec::new();
+    for (&s, rgba) in sizes.iter().zip(rendered) {
+        // Element types have fixed dimensions; catch a render that missed them here rather
+        // than as an opaque encoder error.
+        if rgba.dimensions() != (s, s) {
+            bail!(
+                "{}px render came out {}x{}; ICNS elements must be exactly {}x{}",
+                s,
+                rgba.width(),
+                rgba.height(),
+                s,
+                s
+            );
+        }
+        let start = Instant::now();
+        let legacy = legacy_mask_ostypes(s)
+            .filter(|_| icns_opts.legacy_masks)
+            .map(|(color, mask)| {
+                [
+                    IconElement::new(color, encode_rgb24_element(&rgba, color)),
+                    IconElement::new(mask, encode_mask8_element(&rgba)),
+                ]
+            });
+        if let Some(ostype) = argb_ostype(s).filter(|_| icns_opts.argb_small) {
+            family
+                .elements
+                .push(IconElement::new(ostype, encode_argb_element(&rgba)));
+            family.elements.extend(legacy.into_iter().flatten());
+            record_time(|| format!("encode {}px", s), start.elapsed());
+            continue;
+        }
+        let icon_type = IconType::from_pixel_size(s, s).unwrap();
+        // 48px has no modern type; there the classic pair is the only element.
+        let added = if legacy
+            .as_ref()
+            .is_some_and(|[color, _]| color.ostype == icon_type.ostype())
+        {
+            Ok(())
+        } else {
+            Image::from_data(PixelFormat::RGBA, s, s, rgba.into_raw())
+                .with_context(|| format!("img {}px", s))
+                .and_then(|img| {
+                    family
+                        .add_icon_with_type(&img, icon_type)
+                        .with_context(|| format!("add {}", s))
+                })
+        };
+        record_time(|| format!("encode {}px", s), start.elapsed());
+        match added {
+            Ok(()) => family.elements.extend(legacy.into_iter().flatten()),
+            Err(e) if icns_opts.strip_incomplete => dropped.push((s, e)),
+            Err(e) => return Err(e),
+        }
+    }
+    if !dropped.is_empty() {
+        if dropped.len() == sizes.len() {
+            let (_, first) = dropped.swap_remove(0);
+            return Err(first.context("no size could be encoded"));
+        }
+        diag!(
+            "Warning: dropped {} of {} sizes that failed to encode:",
+            dropped.len(),
+            sizes.len()
+        );
+        for (s, e) in &dropped {
+            diag!("  {}px: {:#}", s, e);
+        }
+    }
+    let mut bytes = Vec::new();
+    family
+        .write(&mut bytes)
+        .with_context(|| format!("write icns {}", out.display()))?;
+    if let Some(max) = icns_opts.max_file_size {
+        let parts = family
+            .elements
+            .iter()
+            .map(|e| (ostype_name(e.ostype), e.data.len()));
+        check_file_size(out, bytes.len(), max, parts.collect())?;
+    }
+    if let Some(parent) = out.parent() {
+        ensure_dir(parent)?;
+    }
+    timed(|| "write".into(), || fs::write(out, &bytes))
+        .with_context(|| format!("write icns {}", out.display()))?;
+    record_report(|| {
+        family
+            .elements
+            .iter()
+            .map(|e| {
+                let size = argb_size(e.ostype)
+                    .or_else(|| IconType::from_ostype(e.ostype).map(|t| t.pixel_width()))
+                    .unwrap_or(0);
+                ReportEntry {
+                    width: size,
+                    height: size,
+                    encoding: ostype_name(e.ostype),
+                    bitcount: None,
+                    bytes: e.data.len(),
+                }
+            })
+            .collect()
+    });
+    Ok(sizes
+        .iter()
+        .copied()
+        .filter(|s| !dropped.iter().any(|(d, _)| d == s))
+        .collect())
+}
+
+// Re-read a freshly written icon, decode every image and compare the sizes found with
+// `expected`. On mismatch the file is removed when `delete_invalid` is set.
+fn verify_output(
+    out: &Path,
+    format: TargetFormat,
+    expected: &[u32],
+    delete_invalid: bool,
+) -> Result<()> {
+    let result = decoded_sizes(out, format).and_then(|found| {
+        let missing: Vec<u32> = expected
+            .iter()
+            .copied()
+            .filter(|s| !found.contains(s))
+            .collect();
+        let unexpected: Vec<u32> = found
+            .iter()
+            .copied()
+            .filter(|s| !expected.contains(s))
+            .collect();
+        if missing.is_empty() && unexpected.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "size mismatch (missing {:?}, unexpected {:?})",
+            missing,
+            unexpected
+        )
+    });
+    match result {
+        Ok(()) => {
+            status!("verified {} ({} sizes)", out.display(), expected.len());
+            Ok(())
+        }
+        Err(e) => {
+            if delete_invalid {
+                fs::remove_file(out).with_context(|| format!("remove {}", out.display()))?;
+                diag!("Warning: removed {}", out.display());
+            }
+            Err(e.context(format!("verification of {} failed", out.display())))
+        }
+    }
+}
+
+// Edge lengths of every image in an .ico/.icns, decoding each one; a declared size that
+// disagrees with the data is an error. Non-square ICO entries count under their longer side.
+fn decoded_sizes(path: &Path, format: TargetFormat) -> Result<Vec<u32>> {
+    let mut sizes = Vec::new();
+    match format {
+        TargetFormat::Ico => {
+            let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+            let dir =
+                ico::IconDir::read(f).with_context(|| format!("read ico {}", path.display()))?;
+            for (i, entry) in dir.entries().iter().enumerate() {
+                let image = entry
+                    .decode()
+                    .with_context(|| format!("decode entry #{}", i))?;
+                let (w, h) = (image.width(), image.height());
+                if w != entry.width() || h != entry.height() {
+                    bail!(IconError::Malformed(format!(
+                        "entry #{} declares {}x{} but decodes to {}x{}",
+                        i,
+                        entry.width(),
+                        entry.height(),
+                        w,
+                        h
+                    )));
+                }
+                sizes.push(w.max(h));
+            }
+        }
+        TargetFormat::Icns => {
+            let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+            let family = icns::IconFamily::read(f)
+                .with_context(|| format!("read icns {}", path.display()))?;
+            for element in &family.elements {
+                if let Some(size) = argb_size(element.ostype) {
+                    decode_argb_element(&element.data, size)
+                        .with_context(|| format!("decode {}", ostype_name(element.ostype)))?;
+                    sizes.push(size);
+                }
+            }
+            for icon_type in family.available_icons() {
+                let image = family
+                    .get_icon_with_type(icon_type)
+                    .with_context(|| format!("decode {:?}", icon_type))?;
+                if image.width() != icon_type.pixel_width()
+                    || image.height() != icon_type.pixel_height()
+                {
+                    bail!(IconError::Malformed(format!(
+                        "{:?} decodes to {}x{}",
+                        icon_type,
+                        image.width(),
+                        image.height()
+                    )));
+                }
+                sizes.push(image.width());
+            }
+        }
+    }
+    sizes.sort_unstable();
+    sizes.dedup();
+    Ok(sizes)
+}
+
+// Build from a directory of images (various sizes)
+fn build_from_dir(
+    dir: &Path,
+    format: TargetFormat,
+    sizes: &[u32],
+    out: &Path,
+    exact_size_required: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let size_map = collect_sized_images(dir)?;
+    if exact_size_required {
+        return build_from_exact_sizes(&size_map, dir, format, sizes, out, dry_run);
+    }
+    // We'll pick a base largest image to scale others if needed.
+    let largest = size_map.last().unwrap().1.clone();
+    let largest_img = load_image(&largest)?;
+    if dry_run {
+        let plan: Vec<(u32, &Path)> = sizes.iter().map(|&s| (s, largest.as_path())).collect();
+        print_build_plan(&plan, out);
+        return Ok(());
+    }
+    let opts = RenderOptions::contain(); // directory mode assumes contain for padding
+    match format {
+        TargetFormat::Ico => build_ico(&largest_img, sizes, &opts, &IcoOptions::default(), out),
+        TargetFormat::Icns => {
+            build_icns(&largest_img, sizes, &opts, &IcnsOptions::default(), out).map(drop)
+        }
+    }
+}
+
+// Files to consider for `build-dir`: a directory's entries, or the matches of a glob
+// pattern such as `assets/*-icon-*.png`.
+fn source_files(source: &Path) -> Result<Vec<PathBuf>> {
+    let pattern = source.to_string_lossy();
+    if !source.is_dir() && pattern.contains(['*', '?', '[']) {
+        let matches =
+            glob::glob(&pattern).with_context(|| format!("invalid glob pattern {}", pattern))?;
+        return Ok(matches.collect::<Result<Vec<_>, _>>()?);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(source).with_context(|| format!("read dir {}", source.display()))? {
+        files.push(entry?.path());
+    }
+    Ok(files)
+}
+
+// Every size comes from its own file (the first one named for it), never from scaling
+// another size; any size without a file, or whose file has other dimensions, is an error.
+fn build_from_exact_sizes(
+    size_map: &[(u32, PathBuf)],
+    dir: &Path,
+    format: TargetFormat,
+    sizes: &[u32],
+    out: &Path,
+    dry_run: bool,
+) -> Result<()> {
+    let mut plan: Vec<(u32, &Path)> = Vec::new();
+    let mut images = Vec::new();
+    let mut missing = Vec::new();
+    let mut mismatched = Vec::new();
+    for &s in sizes {
+        match size_map.iter().find(|(found, _)| *found == s) {
+            Some((_, path)) => {
+                let img = load_image(path)?;
+                if img.dimensions() != (s, s) {
+                    mismatched.push(format!(
+                        "{} is {}x{}, not {}x{}",
+                        path.display(),
+                        img.width(),
+                        img.height(),
+                        s,
+                        s
+                    ));
+                }
+                plan.push((s, path));
+                images.push(img);
+            }
+            None => missing.push(s),
+        }
+    }
+    if !missing.is_empty() || !mismatched.is_empty() {
+        let mut problems = Vec::new();
+        if !missing.is_empty() {
+            problems.push(format!("no source image for size(s) {:?}", missing));
+        }
+        problems.extend(mismatched);
+        bail!(
+            "Not every size is hand-authored in {} (--exact-size-required): {}",
+            dir.display(),
+            problems.join("; ")
+        );
+    }
+    if dry_run {
+        print_build_plan(&plan, out);
+        return Ok(());
+    }
+    // Every file is already at its size, so this only converts it to RGBA.
+    let opts = RenderOptions::contain();
+    let rendered = sizes
+        .iter()
+        .zip(&images)
+        .map(|(&s, img)| resized_rgba(img, s, &opts))
+        .collect();
+    match format {
+        TargetFormat::Ico => write_ico(sizes, rendered, &IcoOptions::default(), out),
+        TargetFormat::Icns => write_icns(sizes, rendered, &IcnsOptions::default(), out).map(drop),
+    }
+}
+
+// Collect (size, path) pairs for images whose filename carries a size, sorted ascending.
+fn collect_sized_images(dir: &Path) -> Result<Vec<(u32, PathBuf)>> {
+    // Map size->path: choose best (exact size) or pick largest for scaling down later.
+    let mut size_map: Vec<(u32, PathBuf)> = Vec::new();
+    for p in source_files(dir)? {
+        if !p.is_file() {
+            continue;
+        }
+        if let Some(ext) = p.extension().and_then(|s| s.to_str()) {
+            match ext.to_ascii_lowercase().as_str() {
+                "png" | "jpg" | "jpeg" => {}
+                _ => continue,
+            };
+        } else {
+            continue;
+        }
+        // Extract size from filename like 16.png or icon-32x32.png etc.
+        let fname = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let mut parsed: Option<u32> = None;
+        for token in fname.split(|c: char| !c.is_ascii_digit()) {
+            if let Ok(v @ 1..) = token.parse::<u32>() {
+                parsed = Some(v);
+                break;
+            }
+        }
+        if let Some(sz) = parsed {
+            size_map.push((sz, p));
+        }
+    }
+    if size_map.is_empty() {
+        bail!("No sized images found in {}", dir.display());
+    }
+    size_map.sort_by_key(|(s, _)| *s);
+    Ok(size_map)
+}
+
+// ============ Build cache ============
+
+// Key for `build --cache-dir`: SHA-256 of the tool version, the build's arguments and the
+// contents of every file they read, so a changed source, option or tool version misses.
+fn build_cache_key(args: &str, files: &[&Path]) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update(args);
+    for path in files {
+        hasher.update(read_input(path)?);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+// Copy a freshly built `out` into the cache as `<key>.<ext>` and record it in the cache's
+// manifest.json under the output path. The entry it replaces for that output is deleted,
+// so the cache holds one file per output.
+fn store_in_cache(dir: &Path, key: &str, ext: &str, out: &Path, source: &Path) -> Result<()> {
+    ensure_dir(dir)?;
+    fs::copy(out, dir.join(format!("{}.{}", key, ext)))
+        .with_context(|| format!("copy {} into {}", out.display(), dir.display()))?;
+    let manifest_path = dir.join("manifest.json");
+    let mut manifest = match fs::read_to_string(&manifest_path) {
+        Ok(text) => serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&text)
+            .with_context(|| format!("parse {}", manifest_path.display()))?,
+        Err(_) => serde_json::Map::new(),
+    };
+    let entry = serde_json::json!({
+        "key": key,
+        "file": format!("{}.{}", key, ext),
+        "source": source.display().to_string(),
+    });
+    if let Some(old) = manifest.insert(out.display().to_string(), entry) {
+        // Outputs built with the same options share a cache file; keep it while any uses it.
+        let stale = old["file"]
+            .as_str()
+            .filter(|&f| !manifest.values().any(|e| e["file"].as_str() == Some(f)));
+        if let Some(file) = stale {
+            let _ = fs::remove_file(dir.join(file));
+        }
+    }
+    let json = serde_json::to_string_pretty(&serde_json::Value::Object(manifest))? + "\n";
+    fs::write(&manifest_path, json).with_context(|| format!("write {}", manifest_path.display()))
+}
+
+// ============ Cursors ============
+
+// Rebuild a .cur from the `<name>.meta.json` written by extracting one. Entries keep the
+// recorded order, encoding and hotspots; image files are resolved next to the JSON.
+fn build_cursor(meta_path: &Path, out: &Path) -> Result<()> {
+    use ico::{IconDirEntry, IconImage};
+    let text =
+        fs::read_to_string(meta_path).with_context(|| format!("read {}", meta_path.display()))?;
+    let meta: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| IconError::Malformed(format!("{}: {}", meta_path.display(), e)))?;
+    let Some(list) = meta["entries"].as_array().filter(|l| !l.is_empty()) else {
+        bail!(IconError::Malformed(format!(
+            "{} has no cursor entries",
+            meta_path.display()
+        )));
+    };
+    let base = meta_path.parent().unwrap_or(Path::new("."));
+    let mut entries = Vec::new();
+    for (i, item) in list.iter().enumerate() {
+        let field = |key: &str| {
+            item[key]
+                .as_u64()
+                .ok_or_else(|| IconError::Malformed(format!("entry #{} is missing '{}'", i, key)))
+        };
+        let Some(file) = item["file"].as_str() else {
+            bail!(IconError::Malformed(format!(
+                "entry #{} is missing 'file'",
+                i
+            )));
+        };
+        let hotspot = (
+            item["hotspot"]["x"].as_u64().unwrap_or(0) as u16,
+            item["hotspot"]["y"].as_u64().unwrap_or(0) as u16,
+        );
+        let rgba = load_image(&base.join(file))?.to_rgba8();
+        let (w, h) = rgba.dimensions();
+        if (w, h) != (field("width")? as u32, field("height")? as u32) || w > 256 || h > 256 {
+            bail!(IconError::Malformed(format!(
+                "{} is {}x{}, expected the recorded size (at most 256px)",
+                file, w, h
+            )));
+        }
+        let mut entry = if item["png"].as_bool().unwrap_or(false) {
+            IcoEntry {
+                width: w,
+                height: h,
+                bitcount: 32,
+                colors: None,
+                data: encode_png(&rgba, None)?,
+                hotspot: None,
+            }
+        } else if item["bitcount"].as_u64() == Some(1) {
+            // Monochrome cursors extract as opaque black and white over clear pixels.
+            IcoEntry {
+                width: w,
+                height: h,
+                bitcount: 1,
+                colors: None,
+                data: encode_mono_dib(&rgba, 128, DEFAULT_ALPHA_THRESHOLD),
+                hotspot: None,
+            }
+        } else {
+            let icon = IconImage::from_rgba_data(w, h, rgba.into_raw());
+            let entry = IcoEntry::from_encoded(
+                &IconDirEntry::encode_as_bmp(&icon)
+                    .with_context(|| format!("encode {} as BMP", file))?,
+            );
+            if let Some(recorded) = item["bitcount"]
+                .as_u64()
+                .filter(|b| ![0, 24, 32].contains(b))
+            {
+                diag!(
+                    "Warning: {} was {}bpp; rebuilt at {}bpp",
+                    file,
+                    recorded,
+                    entry.bitcount
+                );
+            }
+            entry
+        };
+        entry.hotspot = Some(hotspot);
+        entries.push(entry);
+    }
+    if let Some(parent) = out.parent() {
+        ensure_dir(parent)?;
+    }
+    fs::write(out, encode_ico(&entries)).with_context(|| format!("write cursor {}", out.display()))
+}
+
+// ============ Presets ============
+
+// iOS asset catalog icons as (points, scale, idioms); pixels = points * scale.
+// Filenames follow Xcode's `Icon-App-<pt>x<pt>@<scale>x.png` convention.
+const APPLE_TOUCH_ICONS: &[(&str, u32, &[&str])] = &[
+    ("20", 1, &["ipad"]),
+    ("20", 2, &["iphone", "ipad"]),
+    ("20", 3, &["iphone"]),
+    ("29", 1, &["iphone", "ipad"]),
+    ("29", 2, &["iphone", "ipad"]),
+    ("29", 3, &["iphone"]),
+    ("40", 1, &["ipad"]),
+    ("40", 2, &["iphone", "ipad"]),
+    ("40", 3, &["iphone"]),
+    ("60", 2, &["iphone"]),
+    ("60", 3, &["iphone"]),
+    ("76", 1, &["ipad"]),
+    ("76", 2, &["ipad"]),
+    ("83.5", 2, &["ipad"]),
+    ("1024", 1, &["ios-marketing"]),
+];
+
+// Write an AppIcon.appiconset: cover-fitted, opaque PNGs plus the Contents.json Xcode expects.
+fn build_apple_touch(
+    source: &DynamicImage,
+    background: Rgba<u8>,
+    icc_profile: Option<&[u8]>,
+    out_dir: &Path,
+) -> Result<()> {
+    ensure_dir(out_dir)?;
+    let opts = RenderOptions {
+        contain: false,
+        ..RenderOptions::contain()
+    };
+    let mut images = Vec::new();
+    for &(points, scale, idioms) in APPLE_TOUCH_ICONS {
+        let px = (points.parse::<f32>()? * scale as f32).round() as u32;
+        let filename = format!("Icon-App-{}x{}@{}x.png", points, points, scale);
+        let rgba = flatten(&resized_rgba(source, px, &opts), background);
+        save_png(&rgba, &out_dir.join(&filename), icc_profile)?;
+        for idiom in idioms {
+            images.push(format!(
+                "    {{ \"size\": \"{}x{}\", \"idiom\": \"{}\", \"filename\": \"{}\", \"scale\": \"{}x\" }}",
+                points, points, idiom, filename, scale
+            ));
+        }
+    }
+    let contents = format!(
+        "{{\n  \"images\": [\n{}\n  ],\n  \"info\": {{ \"version\": 1, \"author\": \"icon-rust\" }}\n}}\n",
+        images.join(",\n")
+    );
+    let contents_path = out_dir.join("Contents.json");
+    fs::write(&contents_path, contents)
+        .with_context(|| format!("write {}", contents_path.display()))
+}
+
+// Android launcher densities as (qualifier, legacy icon px). Adaptive icon layers are
+// 108dp rather than 48dp, i.e. 9/4 of the legacy size, with content kept in the inner 72dp.
+const ANDROID_DENSITIES: &[(&str, u32)] = &[
+    ("mdpi", 48),
+    ("hdpi", 72),
+    ("xhdpi", 96),
+    ("xxhdpi", 144),
+    ("xxxhdpi", 192),
+];
+
+// A 108dp adaptive foreground layer of `layer` px with the artwork contain-fitted into
+// its central 72dp.
+fn adaptive_foreground(source: &DynamicImage, layer: u32) -> RgbaImage {
+    let inner = layer * 2 / 3;
+    let mut foreground = RgbaImage::new(layer, layer);
+    let offset = ((layer - inner) / 2) as i64;
+    imageops::replace(
+        &mut foreground,
+        &resized_rgba(source, inner, &RenderOptions::contain()),
+        offset,
+        offset,
+    );
+    foreground
+}
+
+/// Adaptive icon output for the Android preset.
+#[derive(Clone, Debug)]
+struct AndroidAdaptive {
+    background: Rgba<u8>,
+}
+
+// Write mipmap-<density>/<name>.png, plus foreground/background layers and the
+// mipmap-anydpi-v26 XML when adaptive output is requested.
+fn build_android(
+    source: &DynamicImage,
+    name: &str,
+    adaptive: Option<&AndroidAdaptive>,
+    icc_profile: Option<&[u8]>,
+    res_dir: &Path,
+) -> Result<()> {
+    let opts = RenderOptions::contain();
+    let save = |rgba: &RgbaImage, dir: &Path, file: String| -> Result<()> {
+        ensure_dir(dir)?;
+        save_png(rgba, &dir.join(file), icc_profile)
+    };
+    for &(density, px) in ANDROID_DENSITIES {
+        let dir = res_dir.join(format!("mipmap-{}", density));
+        save(
+            &resized_rgba(source, px, &opts),
+            &dir,
+            format!("{}.png", name),
+        )?;
+        if let Some(adaptive) = adaptive {
+            let layer = px * 9 / 4;
+            let foreground = adaptive_foreground(source, layer);
+            save(&foreground, &dir, format!("{}_foreground.png", name))?;
+            let background = RgbaImage::from_pixel(layer, layer, adaptive.background);
+            save(&background, &dir, format!("{}_background.png", name))?;
+        }
+    }
+    if adaptive.is_some() {
+        let dir = res_dir.join("mipmap-anydpi-v26");
+        ensure_dir(&dir)?;
+        let xml = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+                "<adaptive-icon xmlns:android=\"http://schemas.android.com/apk/res/android\">\n",
+                "    <background android:drawable=\"@mipmap/{}_background\"/>\n",
+                "    <foreground android:drawable=\"@mipmap/{}_foreground\"/>\n",
+                "</adaptive-icon>\n",
+            ),
+            name, name
+        );
+        let xml_path = dir.join(format!("{}.xml", name));
+        fs::write(&xml_path, xml).with_context(|| format!("write {}", xml_path.display()))?;
+    }
+    Ok(())
+}
+
+// Sizes of the freedesktop hicolor theme's `<size>x<size>/apps` directories.
+const LINUX_HICOLOR_SIZES: &[u32] = &[16, 22, 24, 32, 48, 64, 128, 256];
+
+// Write hicolor/<size>x<size>/apps/<name>.png under `icons_dir`, the layout desktop
+// environments search per the XDG icon theme spec.
+fn build_linux(
+    source: &DynamicImage,
+    name: &str,
+    icc_profile: Option<&[u8]>,
+    icons_dir: &Path,
+) -> Result<()> {
+    let opts = RenderOptions::contain();
+    let progress = progress_bar(LINUX_HICOLOR_SIZES.len());
+    let rendered = render_sizes(source, LINUX_HICOLOR_SIZES, &opts, &progress);
+    progress.finish_and_clear();
+    for (&size, rgba) in LINUX_HICOLOR_SIZES.iter().zip(rendered) {
+        let dir = icons_dir
+            .join("hicolor")
+            .join(format!("{}x{}", size, size))
+            .join("apps");
+        ensure_dir(&dir)?;
+        save_png(&rgba, &dir.join(format!("{}.png", name)), icc_profile)?;
+    }
+    Ok(())
+}
+
+// Sizes stored in favicon.ico, plus the PNGs web app manifests ask for.
+const FAVICON_ICO_SIZES: &[u32] = &[16, 32, 48];
+const FAVICON_PNGS: &[(&str, u32)] = &[("icon-192.png", 192), ("icon-512.png", 512)];
+
+// Write favicon.ico and the manifest PNGs into `out_dir`. The optional manifest snippet is
+// assembled from the files as written, so its entries can't drift from the output.
+fn build_favicon(
+    source: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    out_dir: &Path,
+    manifest_json: Option<&Path>,
+    src_prefix: &str,
+) -> Result<()> {
+    ensure_dir(out_dir)?;
+    let opts = RenderOptions::contain();
+    let ico_opts = IcoOptions {
+        icc_profile: icc_profile.map(<[u8]>::to_vec),
+        ..IcoOptions::default()
+    };
+    build_ico(
+        source,
+        FAVICON_ICO_SIZES,
+        &opts,
+        &ico_opts,
+        &out_dir.join("favicon.ico"),
+    )?;
+    let ico_sizes: Vec<String> = FAVICON_ICO_SIZES
+        .iter()
+        .map(|s| format!("{}x{}", s, s))
+        .collect();
+    let mut icons = vec![serde_json::json!({
+        "src": format!("{}favicon.ico", src_prefix),
+        "sizes": ico_sizes.join(" "),
+        "type": "image/x-icon",
+    })];
+    for &(file, size) in FAVICON_PNGS {
+        save_png(
+            &resized_rgba(source, size, &opts),
+            &out_dir.join(file),
+            icc_profile,
+        )?;
+        icons.push(serde_json::json!({
+            "src": format!("{}{}", src_prefix, file),
+            "sizes": format!("{}x{}", size, size),
+            "type": "image/png",
+        }));
+    }
+    if let Some(path) = manifest_json {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            ensure_dir(parent)?;
+        }
+        let manifest = serde_json::json!({ "icons": icons });
+        fs::write(path, serde_json::to_string_pretty(&manifest)? + "\n")
+            .with_context(|| format!("write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// ============ Maskable check ============
+
+/// Safe zone a launcher mask is guaranteed to keep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum SafeZone {
+    /// Android adaptive icons: a 66dp circle in the 108dp layer
+    Android,
+    /// W3C maskable web icons: a circle 80% of the icon's width
+    Web,
+}
+
+impl SafeZone {
+    /// Diameter of the safe circle as a fraction of the icon's side.
+    fn diameter(self) -> f32 {
+        match self {
+            SafeZone::Android => 66.0 / 108.0,
+            SafeZone::Web => 0.8,
+        }
+    }
+}
+
+// Alpha-weighted share of the artwork lying outside the centered safe circle, plus the
+// mask itself (true = outside) for the preview.
+fn outside_safe_zone(img: &RgbaImage, zone: SafeZone) -> (f64, Vec<bool>) {
+    let (w, h) = img.dimensions();
+    let radius = zone.diameter() * w.min(h) as f32 / 2.0;
+    let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+    let mut outside = Vec::with_capacity((w * h) as usize);
+    let (mut total, mut clipped) = (0u64, 0u64);
+    for (x, y, p) in img.enumerate_pixels() {
+        // Measure from pixel centers.
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        let out = dx * dx + dy * dy > radius * radius;
+        outside.push(out);
+        total += p[3] as u64;
+        if out {
+            clipped += p[3] as u64;
+        }
+    }
+    let fraction = if total == 0 {
+        0.0
+    } else {
+        clipped as f64 / total as f64
+    };
+    (fraction, outside)
+}
+
+// Preview: outside the safe zone, transparent areas turn gray and artwork turns red.
+fn safe_zone_overlay(img: &RgbaImage, outside: &[bool]) -> RgbaImage {
+    let mut out = img.clone();
+    for (p, &out_of_zone) in out.pixels_mut().zip(outside) {
+        if !out_of_zone {
+            continue;
+        }
+        let a = p[3] as u16;
+        let mix =
+            |c: u8, target: u8| ((c as u16 * (255 - a / 2) + target as u16 * (a / 2)) / 255) as u8;
+        *p = if a == 0 {
+            Rgba([128, 128, 128, 96])
+        } else {
+            Rgba([mix(p[0], 255), mix(p[1], 0), mix(p[2], 0), p[3]])
+        };
+    }
+    out
+}
+
+// Report how much of the artwork a launcher mask could clip, failing above `max_outside`.
+fn check_maskable(
+    input: &Path,
+    zone: SafeZone,
+    as_source: bool,
+    max_outside: f64,
+    overlay: Option<&Path>,
+) -> Result<()> {
+    let source = load_image(input)?;
+    let img = if as_source {
+        // The xxxhdpi foreground layer `android --adaptive` would write.
+        adaptive_foreground(&source, 432)
+    } else {
+        source.to_rgba8()
+    };
+    let (fraction, outside) = outside_safe_zone(&img, zone);
+    if let Some(path) = overlay {
+        if let Some(parent) = path.parent() {
+            ensure_dir(parent)?;
+        }
+        save_png(&safe_zone_overlay(&img, &outside), path, None)?;
+    }
+    status!(
+        "{}: {:.2}% of the artwork lies outside the {:?} safe zone (limit {:.2}%)",
+        input.display(),
+        fraction * 100.0,
+        zone,
+        max_outside * 100.0
+    );
+    if fraction > max_outside {
+        bail!(
+            "{} would be clipped by a launcher mask; keep important content inside the central {:.0}% circle",
+            input.display(),
+            zone.diameter() * 100.0
+        );
+    }
+    Ok(())
+}
+
+// ============ Text ============
+
+// Rasterize a single line of text centered on a transparent size x size square,
+// scaled so its ink bounds fill 80% of the square.
+fn render_text(font_data: Vec<u8>, text: &str, size: u32, color: Rgba<u8>) -> Result<RgbaImage> {
+    render_text_box(font_data, text, size, size, color)
+}
+
+// Like `render_text`, on a width x height box: the ink bounds fill 80% of whichever
+// side is tighter.
+fn render_text_box(
+    font_data: Vec<u8>,
+    text: &str,
+    width: u32,
+    height: u32,
+    color: Rgba<u8>,
+) -> Result<RgbaImage> {
+    use ab_glyph::{Font, FontVec, OutlinedGlyph, PxScale, Rect, ScaleFont, point};
+    let font = FontVec::try_from_vec(font_data).map_err(|_| {
+        IconError::Unsupported("Font is not a readable TrueType/OpenType file".into())
+    })?;
+    let layout = |scale: PxScale| -> (Vec<OutlinedGlyph>, Option<Rect>) {
+        let scaled = font.as_scaled(scale);
+        let mut glyphs = Vec::new();
+        let mut bounds: Option<Rect> = None;
+        let mut caret = 0.0;
+        let mut prev = None;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(prev) = prev {
+                caret += scaled.kern(prev, id);
+            }
+            let glyph = id.with_scale_and_position(scale, point(caret, scaled.ascent()));
+            caret += scaled.h_advance(id);
+            prev = Some(id);
+            // Whitespace and bitmap-only glyphs (e.g. color emoji) have no outline.
+            if let Some(outlined) = font.outline_glyph(glyph) {
+                let r = outlined.px_bounds();
+                bounds = Some(match bounds {
+                    Some(b) => Rect {
+                        min: point(b.min.x.min(r.min.x), b.min.y.min(r.min.y)),
+                        max: point(b.max.x.max(r.max.x), b.max.y.max(r.max.y)),
+                    },
+                    None => r,
+                });
+                glyphs.push(outlined);
+            }
+        }
+        (glyphs, bounds)
+    };
+    let probe = width.max(height) as f32;
+    let Some(b) = layout(PxScale::from(probe)).1 else {
+        bail!("Font has no outlines for {:?}", text);
+    };
+    let fit = (width as f32 * 0.8 / b.width()).min(height as f32 * 0.8 / b.height());
+    let (glyphs, bounds) = layout(PxScale::from(probe * fit));
+    // Glyphs scaled down to nothing (e.g. on a tiny label strip) lose their outlines.
+    let Some(b) = bounds else {
+        bail!("{:?} is too small to draw at {}x{}", text, width, height);
+    };
+    let ox = (width as f32 - b.width()) / 2.0 - b.min.x;
+    let oy = (height as f32 - b.height()) / 2.0 - b.min.y;
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([color[0], color[1], color[2], 0]));
+    for glyph in glyphs {
+        let r = glyph.px_bounds();
+        glyph.draw(|x, y, coverage| {
+            let px = (r.min.x + ox).round() as i64 + x as i64;
+            let py = (r.min.y + oy).round() as i64 + y as i64;
+            if px < 0 || py < 0 || px >= width as i64 || py >= height as i64 {
+                return;
+            }
+            let alpha = (coverage.clamp(0.0, 1.0) * color[3] as f32).round() as u8;
+            let p = canvas.get_pixel_mut(px as u32, py as u32);
+            // Overlapping glyph edges keep the stronger coverage.
+            p.0[3] = p.0[3].max(alpha);
+        });
+    }
+    Ok(canvas)
+}
+
+/// Corner of the icon that `--label` draws its ribbon across.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum LabelCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// A `--label` ribbon: a diagonal band of `color` across `corner`, with `text` on it.
+#[derive(Clone, Debug)]
+struct Label {
+    text: String,
+    font: Vec<u8>,
+    corner: LabelCorner,
+    color: Rgba<u8>,
+}
+
+// Distance of the ribbon's centerline from its corner and the ribbon's thickness,
+// as shares of the icon size.
+const LABEL_OFFSET: f32 = 0.3;
+const LABEL_THICKNESS: f32 = 0.16;
+
+// Draw the ribbon over an already resized square canvas. The text is rendered flat onto a
+// strip the length of the ribbon's inner edge, then sampled along the diagonal; each pixel
+// averages 4x4 samples so the band edges stay smooth at small sizes.
+fn apply_label(canvas: &mut RgbaImage, label: &Label) {
+    const SAMPLES: u32 = 4;
+    let size = canvas.width() as f32;
+    let offset = size * LABEL_OFFSET;
+    let thickness = (size * LABEL_THICKNESS).max(1.0);
+    let length = (2.0 * offset - thickness).max(1.0);
+    // White text on dark ribbons, black on light ones.
+    let luma = 0.299 * label.color[0] as f32
+        + 0.587 * label.color[1] as f32
+        + 0.114 * label.color[2] as f32;
+    let ink = if luma > 160.0 {
+        Rgba([0, 0, 0, 0xFF])
+    } else {
+        Rgba([0xFF, 0xFF, 0xFF, 0xFF])
+    };
+    // A blank label (or one the font can't draw) still gets its ribbon.
+    let strip = render_text_box(
+        label.font.clone(),
+        &label.text,
+        length.ceil() as u32,
+        thickness.ceil() as u32,
+        ink,
+    )
+    .ok();
+    let (corner, inward) = match label.corner {
+        LabelCorner::TopLeft => ((0.0, 0.0), (1.0, 1.0)),
+        LabelCorner::TopRight => ((size, 0.0), (-1.0, 1.0)),
+        LabelCorner::BottomLeft => ((0.0, size), (1.0, -1.0)),
+        LabelCorner::BottomRight => ((size, size), (-1.0, -1.0)),
+    };
+    let h = std::f32::consts::FRAC_1_SQRT_2;
+    let center = (
+        corner.0 + inward.0 * h * offset,
+        corner.1 + inward.1 * h * offset,
+    );
+    // Text runs left to right along the ribbon, with its "down" pointing down the canvas.
+    let along = if inward.0 * inward.1 > 0.0 {
+        (h, -h)
+    } else {
+        (h, h)
+    };
+    let down = (-along.1, along.0);
+    let step = 1.0 / SAMPLES as f32;
+    for (x, y, p) in canvas.enumerate_pixels_mut() {
+        let mut covered = 0u32;
+        let mut text = 0.0;
+        for sy in 0..SAMPLES {
+            for sx in 0..SAMPLES {
+                let dx = x as f32 + (sx as f32 + 0.5) * step - center.0;
+                let dy = y as f32 + (sy as f32 + 0.5) * step - center.1;
+                let v = dx * down.0 + dy * down.1 + thickness / 2.0;
+                if !(0.0..thickness).contains(&v) {
+                    continue;
+                }
+                covered += 1;
+                let u = dx * along.0 + dy * along.1 + length / 2.0;
+                if let (Some(strip), true) = (&strip, (0.0..length).contains(&u)) {
+                    let t = strip.get_pixel(
+                        (u as u32).min(strip.width() - 1),
+                        (v as u32).min(strip.height() - 1),
+                    );
+                    text += t[3] as f32 / 255.0;
+                }
+            }
+        }
+        if covered == 0 {
+            continue;
+        }
+        let text = text / covered as f32;
+        let mix = |c: usize| label.color[c] as f32 * (1.0 - text) + ink[c] as f32 * text;
+        let coverage = covered as f32 / (SAMPLES * SAMPLES) as f32;
+        let alpha = label.color[3] as f32 / 255.0 * coverage;
+        // Source-over the ribbon pixel onto the canvas (straight alpha).
+        let dst_alpha = p[3] as f32 / 255.0;
+        let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+        if out_alpha <= 0.0 {
+            continue;
+        }
+        for c in 0..3 {
+            let blended = (mix(c) * alpha + p[c] as f32 * dst_alpha * (1.0 - alpha)) / out_alpha;
+            p.0[c] = blended.round().clamp(0.0, 255.0) as u8;
+        }
+        p.0[3] = (out_alpha * 255.0).round() as u8;
+    }
+}
+
+// ============ Compose ============
+
+/// A layer image and where its top-left corner lands on the canvas.
+#[derive(Clone, Debug)]
+struct Layer {
+    path: PathBuf,
+    x: i64,
+    y: i64,
+}
+
+// "path@x,y", or a bare path for (0, 0). Splits on the last '@' so paths may contain one.
+fn parse_layer(s: &str) -> Result<Layer, String> {
+    let Some((path, offset)) = s.rsplit_once('@') else {
+        return Ok(Layer {
+            path: PathBuf::from(s),
+            x: 0,
+            y: 0,
+        });
+    };
+    let coords = offset
+        .split_once(',')
+        .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)));
+    let Some((x, y)) = coords else {
+        return Err(format!("invalid layer '{}', expected PATH@X,Y", s));
+    };
+    Ok(Layer {
+        path: PathBuf::from(path),
+        x,
+        y,
+    })
+}
+
+// Alpha-composite layers in order (first = bottom) onto a transparent square canvas.
+// Layers keep their own pixel size; anything outside the canvas is clipped.
+fn compose_layers(layers: &[Layer], canvas_size: u32) -> Result<RgbaImage> {
+    let mut canvas = RgbaImage::new(canvas_size, canvas_size);
+    for layer in layers {
+        let img = load_image(&layer.path)?.to_rgba8();
+        imageops::overlay(&mut canvas, &img, layer.x, layer.y);
+    }
+    Ok(canvas)
+}
+
+// ============ Extract ============
+
+const PNG_SIG: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Tie-breakers applied, in order, between ICO entries of equal area.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Prefer {
+    /// Larger encoded byte size
+    Largest,
+    /// Smaller encoded byte size (most compressed)
+    Smallest,
+    /// PNG-encoded entries
+    Png,
+    /// BMP/DIB-encoded entries
+    Bmp,
+    /// Higher declared bit depth
+    HighestBpp,
+}
+
+/// Options shared by the extraction commands.
+#[derive(Debug)]
+struct ExtractOptions {
+    debug: bool,
+    dry_run: bool,
+    prefer: Vec<Prefer>,
+    strict: bool,
+    all: bool,
+    /// Write the chosen image at every standard size instead of as is.
+    keep_largest_only: bool,
+    /// How `keep_largest_only` fits the image into each size.
+    fit: Fit,
+    /// Leave existing files alone when their bytes already match the output.
+    skip_unchanged: bool,
+    /// Downscale decoded images so neither side exceeds this many pixels.
+    max_dimension: Option<u32>,
+    /// Write opaque gray or few-color images as grayscale/palette PNGs instead of RGBA.
+    preserve_color_type: bool,
+    /// File name pattern replacing the default `<w>x<h>.png`.
+    output_template: Option<OutputTemplate>,
+    /// Warn when semi-transparent edges look premultiplied or matted.
+    pre_multiply_check: bool,
+    /// Print the parsed DIB header and layout of every BMP entry that is decoded.
+    verbose_dib: bool,
+    /// List the directory entries with their indices before extracting.
+    list_entries: bool,
+    /// Extract this directory entry instead of the best-ranked one.
+    index: Option<usize>,
+    /// Skip entries that can't be read or decoded instead of failing.
+    tolerant: bool,
+    /// Write each entry's stored PNG/DIB bytes as they are instead of decoding them.
+    raw: bool,
+    /// Also write the AND mask of each BMP entry as a black-and-white PNG.
+    mask: bool,
+    /// Color composited under transparent pixels when the output format has no alpha (JPEG).
+    matte: Rgba<u8>,
+    /// Collects outputs as entries of a `.zip` instead of loose files in `out_dir`.
+    archive: Option<ZipSink>,
+}
+
+/// In-memory zip written to disk once extraction has succeeded.
+struct ZipSink(RefCell<zip::ZipWriter<Cursor<Vec<u8>>>>);
+
+impl std::fmt::Debug for ZipSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ZipSink")
+    }
+}
+
+/// `--output-template` split into literal text and placeholders.
+#[derive(Clone, Debug)]
+struct OutputTemplate(Vec<TemplatePart>);
+
+#[derive(Clone, Debug)]
+enum TemplatePart {
+    Text(String),
+    Width,
+    Height,
+    Bpp,
+    Index,
+}
+
+// Parsed while the command line is read, so a bad template fails before anything is written.
+fn parse_output_template(s: &str) -> Result<OutputTemplate, String> {
+    if s.contains(['/', '\\']) {
+        return Err(format!(
+            "invalid template '{}': must be a file name, not a path",
+            s
+        ));
+    }
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            parts.push(TemplatePart::Text(rest[..open].to_string()));
+        }
+        let Some(close) = rest[open..].find('}') else {
+            return Err(format!("invalid template '{}': unclosed '{{'", s));
+        };
+        parts.push(match &rest[open + 1..open + close] {
+            "w" => TemplatePart::Width,
+            "h" => TemplatePart::Height,
+            "bpp" => TemplatePart::Bpp,
+            "index" => TemplatePart::Index,
+            other => {
+                return Err(format!(
+                    "invalid template '{}': unknown placeholder {{{}}} (expected {{w}}, {{h}}, {{bpp}} or {{index}})",
+                    s, other
+                ));
+            }
+        });
+        rest = &rest[open + close + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(rest.to_string()));
+    }
+    match Path::new(s).extension().and_then(|e| e.to_str()) {
+        None => parts.push(TemplatePart::Text(".png".into())),
+        Some(ext) if ext.eq_ignore_ascii_case("png") => {}
+        Some(ext) => {
+            return Err(format!(
+                "invalid template '{}': extract writes PNG files, not .{}",
+                s, ext
+            ));
+        }
+    }
+    Ok(OutputTemplate(parts))
+}
+
+impl OutputTemplate {
+    fn render(&self, width: u32, height: u32, bpp: u16, index: usize) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.clone(),
+                TemplatePart::Width => width.to_string(),
+                TemplatePart::Height => height.to_string(),
+                TemplatePart::Bpp => bpp.to_string(),
+                TemplatePart::Index => index.to_string(),
+            })
+            .collect()
+    }
+}
+
+// File name for an extracted image: the template if given, else `default`.
+fn output_name(
+    opts: &ExtractOptions,
+    rgba: &RgbaImage,
+    bpp: u16,
+    index: usize,
+    default: String,
+) -> String {
+    match &opts.output_template {
+        Some(template) => template.render(rgba.width(), rgba.height(), bpp, index),
+        None => default,
+    }
+}
+
+// Apply --max-dimension, keeping the aspect ratio; smaller images are left as is.
+fn clamp_dimension(rgba: RgbaImage, opts: &ExtractOptions) -> RgbaImage {
+    match opts.max_dimension {
+        Some(max) if rgba.width().max(rgba.height()) > max => {
+            if opts.debug {
+                diag!(
+                    "[debug] downscaling {}x{} to fit {}px",
+                    rgba.width(),
+                    rgba.height(),
+                    max
+                );
+            }
+            DynamicImage::ImageRgba8(rgba)
+                .resize(max, max, FilterType::Lanczos3)
+                .to_rgba8()
+        }
+        _ => rgba,
+    }
+}
+
+// Sizes written by `extract --keep-largest-only`.
+const EXTRACT_SIZE_SET: &[u32] = &[16, 24, 32, 48, 64, 128, 256, 512];
+
+// Fit the chosen image to each standard size it covers; sizes above it are skipped since
+// enlarging would only blur. `Fit::Both` writes a `_contain` and a `_cover` file per size.
+fn write_size_set(
+    rgba: &RgbaImage,
+    out_dir: &Path,
+    name_prefix: &str,
+    bpp: u16,
+    index: usize,
+    icc_profile: Option<&[u8]>,
+    opts: &ExtractOptions,
+) -> Result<()> {
+    let largest = rgba.width().max(rgba.height());
+    let (sizes, skipped): (Vec<u32>, Vec<u32>) =
+        EXTRACT_SIZE_SET.iter().partition(|&&s| s <= largest);
+    if sizes.is_empty() {
+        bail!(
+            "largest image is {}x{}, below the smallest standard size {}",
+            rgba.width(),
+            rgba.height(),
+            EXTRACT_SIZE_SET[0]
+        );
+    }
+    if !skipped.is_empty() {
+        diag!(
+            "Warning: largest image is {}x{}; not upscaling to {:?}",
+            rgba.width(),
+            rgba.height(),
+            skipped
+        );
+    }
+    let source = DynamicImage::ImageRgba8(rgba.clone());
+    let variants: &[(bool, &str)] = match opts.fit {
+        Fit::Contain => &[(true, "")],
+        Fit::Cover => &[(false, "")],
+        Fit::Auto if transparent_share(&source) >= FIT_AUTO_TRANSPARENT_MIN => &[(true, "")],
+        Fit::Auto => &[(false, "")],
+        Fit::Both => &[(true, "_contain"), (false, "_cover")],
+    };
+    for s in sizes {
+        for &(contain, suffix) in variants {
+            let render = RenderOptions {
+                contain,
+                ..RenderOptions::contain()
+            };
+            let resized = resized_rgba(&source, s, &render);
+            let name = name_prefix.to_string()
+                + &output_name(
+                    opts,
+                    &resized,
+                    bpp,
+                    index,
+                    format!("{}x{}{}.png", s, s, suffix),
+                );
+            write_png(&resized, &out_dir.join(name), icc_profile, opts)?;
+        }
+    }
+    Ok(())
+}
+
+// Semi-transparent pixels below this alpha are checked against their opaque neighbours.
+const FRINGE_MAX_ALPHA: u8 = 128;
+// Neighbours at or above this alpha count as opaque.
+const FRINGE_OPAQUE_ALPHA: u8 = 224;
+// Mean luma difference (0-255) from which edges are reported as fringed.
+const FRINGE_MIN_DIFF: f64 = 40.0;
+// Too few edge pixels say nothing; tiny or hard-edged icons are skipped.
+const FRINGE_MIN_PIXELS: usize = 16;
+
+/// Systematic color shift of semi-transparent edge pixels.
+struct Fringe {
+    pixels: usize,
+    /// Mean luma of the edge pixels minus that of their opaque neighbours.
+    mean_diff: f64,
+}
+
+// Straight-alpha edges should roughly keep the color of the artwork they fade out of.
+// Edges stored premultiplied (or matted against a background) are shifted toward black
+// (or the matte) instead, which shows as a dark or light fringe once composited.
+fn detect_fringe(rgba: &RgbaImage) -> Option<Fringe> {
+    let luma = |p: &Rgba<u8>| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64;
+    let (w, h) = rgba.dimensions();
+    let (mut pixels, mut total) = (0usize, 0.0);
+    for (x, y, p) in rgba.enumerate_pixels() {
+        if p[3] == 0 || p[3] >= FRINGE_MAX_ALPHA {
+            continue;
+        }
+        let (mut sum, mut n) = (0.0, 0);
+        for ny in y.saturating_sub(2)..(y + 3).min(h) {
+            for nx in x.saturating_sub(2)..(x + 3).min(w) {
+                let q = rgba.get_pixel(nx, ny);
+                if q[3] >= FRINGE_OPAQUE_ALPHA {
+                    sum += luma(q);
+                    n += 1;
+                }
+            }
+        }
+        if n > 0 {
+            total += luma(p) - sum / n as f64;
+            pixels += 1;
+        }
+    }
+    if pixels < FRINGE_MIN_PIXELS {
+        return None;
+    }
+    let mean_diff = total / pixels as f64;
+    (mean_diff.abs() >= FRINGE_MIN_DIFF).then_some(Fringe { pixels, mean_diff })
+}
+
+fn warn_fringe(rgba: &RgbaImage, out_path: &Path) {
+    let Some(fringe) = detect_fringe(rgba) else {
+        return;
+    };
+    let name = out_path.file_name().unwrap_or_default().to_string_lossy();
+    let (shift, cause) = if fringe.mean_diff < 0.0 {
+        (
+            "darker",
+            "the colors look premultiplied by alpha and will show a dark fringe when composited; divide them by alpha before saving as straight alpha",
+        )
+    } else {
+        (
+            "lighter",
+            "the edge was likely matted against a light background and will show a light halo when composited",
+        )
+    };
+    diag!(
+        "Warning: {}: {} semi-transparent edge pixels are on average {:.0} levels {} than the opaque pixels next to them; {}",
+        name,
+        fringe.pixels,
+        fringe.mean_diff.abs(),
+        shift,
+        cause
+    );
+}
+
+// Save a decoded image, or only report the destination in dry-run mode.
+fn write_png(
+    rgba: &RgbaImage,
+    out_path: &Path,
+    icc_profile: Option<&[u8]>,
+    opts: &ExtractOptions,
+) -> Result<()> {
+    if opts.pre_multiply_check {
+        warn_fringe(rgba, out_path);
+    }
+    if opts.dry_run {
+        status!(
+            "would write {} ({}x{})",
+            out_path.display(),
+            rgba.width(),
+            rgba.height()
+        );
+        return Ok(());
+    }
+    let ext = out_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let bytes = if ext.as_deref() == Some("bmp") {
+        encode_bmp(rgba)?
+    } else if matches!(ext.as_deref(), Some("jpg" | "jpeg")) {
+        encode_jpeg(rgba, opts.matte)?
+    } else if ext.as_deref() == Some("webp") {
+        encode_webp(rgba)?
+    } else if opts.preserve_color_type {
+        encode_png_reduced(rgba, icc_profile)?
+    } else {
+        encode_png(rgba, icc_profile)?
+    };
+    write_output(out_path, &bytes, opts)
+}
+
+// Create `out_dir` unless nothing is written there (dry run) or outputs go into a zip.
+fn prepare_out_dir(out_dir: &Path, opts: &ExtractOptions) -> Result<()> {
+    if opts.dry_run || opts.archive.is_some() {
+        return Ok(());
+    }
+    ensure_dir(out_dir)
+}
+
+// Write an extracted file, or add it to the archive under its file name.
+fn write_output(out_path: &Path, bytes: &[u8], opts: &ExtractOptions) -> Result<()> {
+    if let Some(archive) = &opts.archive {
+        let name = out_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let mut zip = archive.0.borrow_mut();
+        zip.start_file(
+            name,
+            zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored),
+        )
+        .with_context(|| format!("add {} to archive", name))?;
+        zip.write_all(bytes)?;
+        if opts.debug {
+            diag!("[debug] archived {}", name);
+        }
+        return Ok(());
+    }
+    if opts.skip_unchanged && fs::read(out_path).is_ok_and(|existing| existing == bytes) {
+        if opts.debug {
+            diag!("[debug] unchanged {}", out_path.display());
+        }
+        return Ok(());
+    }
+    fs::write(out_path, bytes).with_context(|| format!("save {}", out_path.display()))?;
+    if opts.debug {
+        diag!("[debug] wrote {}", out_path.display());
+    }
+    Ok(())
+}
+
+// Write the finished archive to `path`, honoring `--skip-unchanged` for the whole file.
+fn finish_archive(archive: ZipSink, path: &Path, opts: &ExtractOptions) -> Result<()> {
+    let bytes = archive
+        .0
+        .into_inner()
+        .finish()
+        .with_context(|| format!("finish {}", path.display()))?
+        .into_inner();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        ensure_dir(parent)?;
+    }
+    write_output(path, &bytes, opts)
+}
+
+fn extract_ico(path: &Path, out_dir: &Path, opts: &ExtractOptions) -> Result<()> {
+    if input_url(path).is_some() {
+        let data = read_input(path)?;
+        return extract_ico_from(std::io::Cursor::new(data), path, out_dir, "", opts);
+    }
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    extract_ico_from(f, path, out_dir, "", opts)
+}
+
+// `path` only names the source in messages and the cursor meta file. `name_prefix` is
+// prepended to every file written (e.g. "frame3_" for animated cursor frames).
+fn extract_ico_from<R: Read + Seek>(
+    mut f: R,
+    path: &Path,
+    out_dir: &Path,
+    name_prefix: &str,
+    opts: &ExtractOptions,
+) -> Result<()> {
+    let debug = opts.debug;
+    #[derive(Debug, Clone)]
+    struct DirEntry {
+        width: u8,
+        height: u8,
+        bitcount: u16,
+        bytes_in_res: u32,
+        image_offset: u32,
+        is_png: bool,
+        hotspot: Option<(u16, u16)>,
+    }
+    let mut header = [0u8; 6];
+    f.read_exact(&mut header)?;
+    if u16::from_le_bytes([header[0], header[1]]) != 0 {
+        bail!(IconError::Malformed("Invalid ICO reserved".into()));
+    }
+    // Type 1 is an icon, type 2 a cursor (same layout, hotspot instead of planes/bitcount).
+    let cursor = match u16::from_le_bytes([header[2], header[3]]) {
+        1 => false,
+        2 => true,
+        _ => bail!(IconError::Unsupported("Not ICO".into())),
+    };
+    let count = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let mut dir = vec![0u8; 16 * count];
+    f.read_exact(&mut dir)?;
+    let mut entries: Vec<DirEntry> = Vec::with_capacity(count);
+    for i in 0..count {
+        let o = i * 16;
+        let field_a = u16::from_le_bytes([dir[o + 4], dir[o + 5]]);
+        let field_b = u16::from_le_bytes([dir[o + 6], dir[o + 7]]);
+        entries.push(DirEntry {
+            width: dir[o],
+            height: dir[o + 1],
+            bitcount: if cursor { 0 } else { field_b },
+            bytes_in_res: u32::from_le_bytes([dir[o + 8], dir[o + 9], dir[o + 10], dir[o + 11]]),
+            image_offset: u32::from_le_bytes([dir[o + 12], dir[o + 13], dir[o + 14], dir[o + 15]]),
+            is_png: false,
+            hotspot: cursor.then_some((field_a, field_b)),
+        });
+    }
+    // Sniff each entry's encoding so PNG/BMP preferences can be honored. Cursor directories
+    // carry no bit count, so it's taken from the image data instead.
+    for e in entries.iter_mut() {
+        let mut head = [0u8; 16];
+        f.seek(SeekFrom::Start(e.image_offset as u64))?;
+        let ok = f.read_exact(&mut head).is_ok();
+        e.is_png = ok && &head[..8] == PNG_SIG;
+        if cursor && ok {
+            e.bitcount = if e.is_png {
+                32
+            } else {
+                u16::from_le_bytes([head[14], head[15]])
+            };
+        }
+    }
+    // pick largest (treat 0 as 256); ties are broken by the --prefer policy in order
+    let dims = |e: &DirEntry| {
+        let w = if e.width == 0 { 256 } else { e.width as u32 };
+        let h = if e.height == 0 { 256 } else { e.height as u32 };
+        (w, h)
+    };
+    let area = |e: &DirEntry| dims(e).0 * dims(e).1;
+    let compare = |a: &DirEntry, b: &DirEntry| {
+        let mut ord = area(a).cmp(&area(b));
+        for p in &opts.prefer {
+            ord = ord.then_with(|| match p {
+                Prefer::Largest => a.bytes_in_res.cmp(&b.bytes_in_res),
+                Prefer::Smallest => b.bytes_in_res.cmp(&a.bytes_in_res),
+                Prefer::Png => a.is_png.cmp(&b.is_png),
+                Prefer::Bmp => b.is_png.cmp(&a.is_png),
+                Prefer::HighestBpp => a.bitcount.cmp(&b.bitcount),
+            });
+        }
+        ord
+    };
+    let mut best: Option<usize> = None;
+    for (i, e) in entries.iter().enumerate() {
+        if best.is_none_or(|b| compare(e, &entries[b]) == Ordering::Greater) {
+            best = Some(i);
+            if debug {
+                let (w, h) = dims(e);
+                diag!(
+                    "[debug] new best candidate {}x{} bpp={} bytes={} png={}",
+                    w,
+                    h,
+                    e.bitcount,
+                    e.bytes_in_res,
+                    e.is_png
+                );
+            }
+        }
+    }
+    let best = best.ok_or_else(|| IconError::Malformed("No entries".into()))?;
+    if opts.list_entries {
+        for (i, e) in entries.iter().enumerate() {
+            let (w, h) = dims(e);
+            let hotspot = e
+                .hotspot
+                .map(|(x, y)| format!(" hotspot={},{}", x, y))
+                .unwrap_or_default();
+            status!(
+                "{}#{} {}x{} bpp={} {} {} bytes at {}{}{}",
+                name_prefix,
+                i,
+                w,
+                h,
+                e.bitcount,
+                if e.is_png { "png" } else { "bmp" },
+                e.bytes_in_res,
+                e.image_offset,
+                hotspot,
+                if i == best { " (default pick)" } else { "" }
+            );
+        }
+        if opts.index.is_none() {
+            return Ok(());
+        }
+    }
+    // With --tolerant and no explicit choice, every entry is a candidate in rank order and
+    // extraction stops at the first one that decodes.
+    let fallback = opts.tolerant && !opts.all && opts.index.is_none();
+    let selected: Vec<usize> = if opts.all {
+        (0..entries.len()).collect()
+    } else if let Some(index) = opts.index {
+        if index >= entries.len() {
+            bail!(
+                "--index {} is out of range: {} has {} entries",
+                index,
+                path.display(),
+                entries.len()
+            );
+        }
+        vec![index]
+    } else if fallback {
+        let mut ranked: Vec<usize> = (0..entries.len()).collect();
+        // Stable, so ties keep directory order and `best` comes first.
+        ranked.sort_by(|&a, &b| compare(&entries[b], &entries[a]));
+        ranked
+    } else {
+        vec![best]
+    };
+    let file_len = f.seek(SeekFrom::End(0))?;
+    // A zero directory byte means "256 or larger" since Vista, so only a lower bound applies.
+    let matches = |decl: u8, actual: u32| {
+        if decl == 0 {
+            actual >= 256
+        } else {
+            actual == decl as u32
+        }
+    };
+    prepare_out_dir(out_dir, opts)?;
+    let mut used_names = HashSet::new();
+    let mut cursor_meta = Vec::new();
+    let mut decoded = 0;
+    for index in selected {
+        if fallback && decoded > 0 {
+            break;
+        }
+        let e = &entries[index];
+        let (w_decl, h_decl) = dims(e);
+        if debug {
+            diag!(
+                "[debug] chosen entry #{} decl={}x{} bpp={} off={} bytes={} ",
+                index,
+                w_decl,
+                h_decl,
+                e.bitcount,
+                e.image_offset,
+                e.bytes_in_res
+            );
+        }
+        let read = read_entry_blob(&mut f, e.image_offset, e.bytes_in_res, file_len)
+            .and_then(|blob| {
+                let rgba = match opts.raw {
+                    true => None,
+                    false => Some(decode_ico_blob(&blob, debug, opts.verbose_dib)?),
+                };
+                Ok((rgba, blob))
+            })
+            .with_context(|| format!("entry #{} ({}x{})", index, w_decl, h_decl));
+        let (rgba, blob) = match read {
+            Ok(read) => read,
+            Err(err) if opts.tolerant => {
+                diag!("Warning: skipping {}: {:#}", path.display(), err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if fallback && index != best {
+            diag!(
+                "Warning: extracting entry #{} ({}x{}) instead of the unreadable #{}",
+                index,
+                w_decl,
+                h_decl,
+                best
+            );
+        }
+        decoded += 1;
+        let Some(rgba) = rgba else {
+            // Named by directory index first, so `build --from-raw-entries` restores the order.
+            let ext = if blob.starts_with(PNG_SIG) {
+                "png"
+            } else {
+                "bmp"
+            };
+            let name = format!("{}{}_{}x{}.{}", name_prefix, index, w_decl, h_decl, ext);
+            let out_path = out_dir.join(name);
+            if opts.dry_run {
+                status!(
+                    "would write {} ({} bytes as stored)",
+                    out_path.display(),
+                    blob.len()
+                );
+            } else {
+                write_output(&out_path, &blob, opts)?;
+            }
+            continue;
+        };
+        let icc_profile = png_icc_profile(&blob);
+        if !matches(e.width, rgba.width()) || !matches(e.height, rgba.height()) {
+            let msg = format!(
+                "entry #{} declares {}x{} but image data is {}x{}",
+                index,
+                w_decl,
+                h_decl,
+                rgba.width(),
+                rgba.height()
+            );
+            if opts.strict {
+                bail!(IconError::Malformed(format!("{} (--strict)", msg)));
+            }
+            diag!("Warning: {}; naming output after the decoded size", msg);
+        }
+        let decoded_width = rgba.width();
+        let rgba = clamp_dimension(rgba, opts);
+        if opts.keep_largest_only {
+            let icc_profile = icc_profile.as_deref();
+            write_size_set(
+                &rgba,
+                out_dir,
+                name_prefix,
+                e.bitcount,
+                index,
+                icc_profile,
+                opts,
+            )?;
+            continue;
+        }
+        let mut name = name_prefix.to_string()
+            + &output_name(
+                opts,
+                &rgba,
+                e.bitcount,
+                index,
+                format!("{}x{}.png", rgba.width(), rgba.height()),
+            );
+        if !used_names.insert(name.clone()) {
+            // Same-size entries (e.g. different bit depths) are told apart by directory index.
+            let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, "png"));
+            name = format!("{}_{}.{}", stem, index, ext);
+            used_names.insert(name.clone());
+        }
+        if let Some((x, y)) = e.hotspot {
+            // Keep the hotspot on the same pixel if --max-dimension scaled the image.
+            let scale = |v: u16| (v as u32 * rgba.width() / decoded_width) as u16;
+            cursor_meta.push(serde_json::json!({
+                "file": name,
+                "width": rgba.width(),
+                "height": rgba.height(),
+                "bitcount": e.bitcount,
+                "png": e.is_png,
+                "hotspot": { "x": scale(x), "y": scale(y) },
+            }));
+        }
+        write_png(&rgba, &out_dir.join(&name), icc_profile.as_deref(), opts)?;
+        if opts.mask {
+            let Some(mask) = decode_and_mask(&blob)? else {
+                diag!(
+                    "Warning: entry #{} is PNG-encoded and has no AND mask",
+                    index
+                );
+                continue;
+            };
+            let stem = name
+                .rsplit_once('.')
+                .map_or(name.as_str(), |(stem, _)| stem);
+            let mask_path = out_dir.join(format!("{}_mask.png", stem));
+            if opts.dry_run {
+                status!(
+                    "would write {} ({}x{})",
+                    mask_path.display(),
+                    mask.width(),
+                    mask.height()
+                );
+            } else {
+                write_output(&mask_path, &encode_png_reduced(&mask, None)?, opts)?;
+            }
+        }
+    }
+    if decoded == 0 {
+        bail!(IconError::Malformed(format!(
+            "no entry of {} could be read",
+            path.display()
+        )));
+    }
+    // Hotspots only describe images written at their original size, and build-cursor
+    // can't load the headerless DIBs `--raw` writes.
+    if cursor && opts.raw {
+        diag!(
+            "Warning: no .meta.json is written for cursors under --raw, and \
+             build --from-raw-entries packs an .ico without hotspots"
+        );
+    } else if cursor && !opts.keep_largest_only {
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("cursor");
+        let meta_path = out_dir.join(format!("{}{}.meta.json", name_prefix, stem));
+        if cursor_meta.len() < entries.len() {
+            diag!(
+                "Warning: {} lists {} of {}'s {} entries; build-cursor will rebuild only those \
+                 (use --all to keep every entry)",
+                meta_path.display(),
+                cursor_meta.len(),
+                path.display(),
+                entries.len()
+            );
+        }
+        if opts.dry_run {
+            status!("would write {}", meta_path.display());
+            return Ok(());
+        }
+        let meta = serde_json::json!({ "type": "cursor", "entries": cursor_meta });
+        let json = serde_json::to_string_pretty(&meta)? + "\n";
+        write_output(&meta_path, json.as_bytes(), opts)?;
+    }
+    Ok(())
+}
+
+// Read the `len` bytes of an entry at `offset`, failing before allocating when the
+// directory points past the end of the stream.
+fn read_entry_blob<R: Read + Seek>(
+    f: &mut R,
+    offset: u32,
+    len: u32,
+    file_len: u64,
+) -> Result<Vec<u8>> {
+    if offset as u64 + len as u64 > file_len {
+        bail!(IconError::Malformed(format!(
+            "{} bytes at offset {} run past the end of the file ({} bytes)",
+            len, offset, file_len
+        )));
+    }
+    f.seek(SeekFrom::Start(offset as u64))?;
+    let mut blob = vec![0u8; len as usize];
+    f.read_exact(&mut blob)?;
+    Ok(blob)
+}
+
+// Decode a single ICO image resource (embedded PNG or DIB) into RGBA.
+// `verbose_dib` dumps the fields and layout the DIB path works from as soon as each is
+// known, so they are printed even when the entry is then rejected.
+fn decode_ico_blob(blob: &[u8], debug: bool, verbose_dib: bool) -> Result<RgbaImage> {
+    if blob.len() >= 8 && &blob[..8] == PNG_SIG {
+        if debug {
+            diag!("[debug] decoding PNG entry");
+        }
+        let img = image::load_from_memory(blob).with_context(|| "decode PNG")?;
+        return Ok(img.to_rgba8());
+    }
+    // DIB path minimal support (32/24/16bpp + 1/4/8bpp indexed)
+    if verbose_dib {
+        diag!("[dib] blob {} bytes", blob.len());
+    }
+    if blob.len() < 40 {
+        bail!(IconError::Unsupported("Unsupported blob format".into()));
+    }
+    let read_u32 = |off: usize| u32::from_le_bytes(blob[off..off + 4].try_into().unwrap());
+    // 40 = BITMAPINFOHEADER, 52/56 = V2/V3, 108 = BITMAPV4HEADER, 124 = BITMAPV5HEADER.
+    let header_size = read_u32(0) as usize;
+    if header_size < 40 {
+        bail!(IconError::Unsupported("Unsupported DIB header".into()));
+    }
+    if blob.len() < header_size {
+        bail!(IconError::Malformed("Truncated DIB header".into()));
+    }
+    let dib_w = read_u32(4);
+    let dib_h_total = read_u32(8) as i32;
+    if dib_h_total <= 0 {
+        bail!(IconError::Malformed("Invalid DIB height".into()));
+    }
+    let dib_h = (dib_h_total as u32) / 2;
+    let bpp = u16::from_le_bytes(blob[14..16].try_into().unwrap());
+    let compression = read_u32(16);
+    let clr_used = read_u32(32);
+    if verbose_dib {
+        diag!(
+            "[dib] header_size={} width={} height={} ({} per image + mask) bpp={} compression={} clr_used={}",
+            header_size,
+            dib_w,
+            dib_h_total,
+            dib_h,
+            bpp,
+            compression,
+            clr_used
+        );
+    }
+    // Explicit channel masks live inside V2+ headers, but follow a plain 40-byte header.
+    let mut masks_after_header = 0;
+    let masks = match compression {
+        BI_RGB => None,
+        BI_BITFIELDS | BI_ALPHABITFIELDS => {
+            let count = if header_size >= 56 || compression == BI_ALPHABITFIELDS {
+                4
+            } else {
+                3
+            };
+            let at = if header_size >= 52 {
+                40
+            } else {
+                masks_after_header = count * 4;
+                header_size
+            };
+            if blob.len() < at + count * 4 {
+                bail!(IconError::Malformed("Truncated DIB color masks".into()));
+            }
+            let mut m = [0u32; 4];
+            for (i, mask) in m.iter_mut().take(count).enumerate() {
+                *mask = read_u32(at + i * 4);
+            }
+            Some(m)
+        }
+        _ => bail!(IconError::Unsupported("Compressed DIB unsupported".into())),
+    };
+    let data_offset = header_size + masks_after_header;
+    if verbose_dib {
+        diag!(
+            "[dib] channel masks {:08X?}, data offset {}",
+            masks,
+            data_offset
+        );
+    }
+    if matches!(bpp, 32 | 24 | 16) {
+        // BI_RGB 32bpp is BGRA in memory, 24bpp BGR (as `build` writes opaque sizes) and
+        // 16bpp X1R5G5B5.
+        let (masks, has_alpha) = match masks {
+            Some(m) => (m, m[3] != 0),
+            None if bpp == 32 => ([0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0xFF00_0000], true),
+            None if bpp == 24 => ([0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0], false),
+            None => ([0x7C00, 0x03E0, 0x001F, 0], false),
+        };
+        let channels = masks.map(ChannelMask::new);
+        let bytes_pp = bpp as usize / 8;
+        let row_stride = ((dib_w * bpp as u32).div_ceil(32) * 4) as usize;
+        let expected = row_stride * dib_h as usize;
+        if verbose_dib {
+            dib_layout(
+                blob.len(),
+                dib_w,
+                dib_h,
+                row_stride,
+                data_offset,
+                expected,
+                has_alpha,
+            );
+        }
+        if blob.len() < data_offset + expected {
+            bail!(IconError::Malformed(format!("Truncated {}bpp data", bpp)));
+        }
+        let data = &blob[data_offset..data_offset + expected];
+        let mut rgba = RgbaImage::new(dib_w, dib_h);
+        for y in 0..dib_h {
+            let src_row = (dib_h - 1 - y) as usize;
+            for x in 0..dib_w {
+                let i = src_row * row_stride + x as usize * bytes_pp;
+                let px = match bpp {
+                    32 => u32::from_le_bytes(data[i..i + 4].try_into().unwrap()),
+                    24 => u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]),
+                    _ => u16::from_le_bytes([data[i], data[i + 1]]) as u32,
+                };
+                let [r, g, b, a] = channels.map(|c| c.expand(px));
+                rgba.put_pixel(x, y, Rgba([r, g, b, if has_alpha { a } else { 0xFF }]));
+            }
+        }
+        // Without an alpha channel, transparency comes from the AND mask.
+        if !has_alpha {
+            apply_and_mask(&mut rgba, &blob[data_offset + expected..]);
+        }
+        if debug {
+            diag!(
+                "[debug] decoded {}x{} (DIB{}, header {} bytes, masks {:08X?})",
+                dib_w,
+                dib_h,
+                bpp,
+                header_size,
+                masks
+            );
+        }
+        return Ok(rgba);
+    }
+    if masks.is_some() {
+        bail!(IconError::Unsupported(format!(
+            "BI_BITFIELDS unsupported for {}bpp",
+            bpp
+        )));
+    }
+    if matches!(bpp, 1 | 4 | 8) {
+        let palette_len = if clr_used > 0 {
+            clr_used as usize
+        } else {
+            1 << bpp
+        };
+        let palette_bytes = palette_len * 4;
+        if blob.len() < data_offset + palette_bytes {
+            bail!(IconError::Malformed("Truncated palette".into()));
+        }
+        let palette = &blob[data_offset..data_offset + palette_bytes];
+        let row_stride = (dib_w * bpp as u32).div_ceil(32) * 4;
+        let pixel_array_size = (row_stride * dib_h) as usize;
+        let pixel_offset = data_offset + palette_bytes;
+        if verbose_dib {
+            diag!(
+                "[dib] palette {} entries at {}..{}",
+                palette_len,
+                data_offset,
+                data_offset + palette_bytes
+            );
+            dib_layout(
+                blob.len(),
+                dib_w,
+                dib_h,
+                row_stride as usize,
+                pixel_offset,
+                pixel_array_size,
+                false,
+            );
+        }
+        if blob.len() < pixel_offset + pixel_array_size {
+            bail!(IconError::Malformed("Truncated pixel array".into()));
+        }
+        let pixels = &blob[pixel_offset..pixel_offset + pixel_array_size];
+        let mut rgba = RgbaImage::new(dib_w, dib_h);
+        for y in 0..dib_h {
+            let src_row = (dib_h - 1 - y) as usize;
+            let row_start = src_row * row_stride as usize;
+            for x in 0..dib_w {
+                // Indices are packed most significant bits first.
+                let bit = x as usize * bpp as usize;
+                let byte = pixels[row_start + bit / 8];
+                let idx = (byte >> (8 - bpp as usize - bit % 8)) as usize & ((1 << bpp) - 1);
+                let base = (idx.min(palette_len - 1)) * 4;
+                let b = palette[base];
+                let g = palette[base + 1];
+                let r = palette[base + 2];
+                rgba.put_pixel(x, y, Rgba([r, g, b, 0xFF]));
+            }
+        }
+        apply_and_mask(&mut rgba, &blob[pixel_offset + pixel_array_size..]);
+        if debug {
+            diag!("[debug] decoded {}x{} (DIB{})", dib_w, dib_h, bpp);
+        }
+        return Ok(rgba);
+    }
+    bail!(IconError::Unsupported(format!(
+        "Unsupported DIB bpp={}",
+        bpp
+    )))
+}
+
+// DIB compression values accepted by the decoder.
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+/// One channel of a BI_BITFIELDS color mask, as a bit shift and width.
+#[derive(Clone, Copy, Debug)]
+struct ChannelMask {
+    shift: u32,
+    bits: u32,
+}
+
+impl ChannelMask {
+    fn new(mask: u32) -> Self {
+        if mask == 0 {
+            return ChannelMask { shift: 0, bits: 0 };
+        }
+        let shift = mask.trailing_zeros();
+        ChannelMask {
+            shift,
+            bits: (mask >> shift).trailing_ones(),
+        }
+    }
+
+    // Scale the channel value to 0..=255 (0 when the mask is empty).
+    fn expand(&self, px: u32) -> u8 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let max = (1u64 << self.bits) - 1;
+        let v = (px as u64 >> self.shift) & max;
+        ((v * 255 + max / 2) / max) as u8
+    }
+}
+
+// --verbose-dib: where the pixel rows and the AND mask should sit in a blob of `len` bytes.
+fn dib_layout(
+    len: usize,
+    w: u32,
+    h: u32,
+    row_stride: usize,
+    pixel_offset: usize,
+    pixel_bytes: usize,
+    has_alpha: bool,
+) {
+    let mask_stride = w.div_ceil(32) as usize * 4;
+    let mask_offset = pixel_offset + pixel_bytes;
+    diag!(
+        "[dib] pixels: stride {} x {} rows = {} bytes at {}..{}",
+        row_stride,
+        h,
+        pixel_bytes,
+        pixel_offset,
+        mask_offset
+    );
+    diag!(
+        "[dib] AND mask: stride {} x {} rows = {} bytes at {}..{}{}",
+        mask_stride,
+        h,
+        mask_stride * h as usize,
+        mask_offset,
+        mask_offset + mask_stride * h as usize,
+        if has_alpha {
+            " (ignored, alpha channel used)"
+        } else {
+            ""
+        }
+    );
+    let end = mask_offset + mask_stride * h as usize;
+    match len.checked_sub(end) {
+        Some(0) => diag!("[dib] blob ends with the mask"),
+        Some(extra) => diag!("[dib] {} trailing bytes after the mask", extra),
+        None => diag!("[dib] blob is {} bytes short of pixels + mask", end - len),
+    }
+}
+
+// The 1bpp AND mask of a DIB entry as an opaque black-and-white image, white where the mask
+// marks a pixel transparent. `None` for PNG entries, which carry no mask.
+fn decode_and_mask(blob: &[u8]) -> Result<Option<RgbaImage>> {
+    if blob.starts_with(PNG_SIG) {
+        return Ok(None);
+    }
+    if blob.len() < 40 {
+        bail!(IconError::Unsupported("Unsupported blob format".into()));
+    }
+    let read_u32 = |off: usize| u32::from_le_bytes(blob[off..off + 4].try_into().unwrap());
+    let header_size = read_u32(0) as usize;
+    let (w, h) = (read_u32(4), (read_u32(8) as i32).max(0) as u32 / 2);
+    let bpp = u16::from_le_bytes([blob[14], blob[15]]) as u32;
+    let compression = read_u32(16);
+    let clr_used = read_u32(32) as usize;
+    // Same layout rules as decode_ico_blob: channel masks after a plain 40-byte header,
+    // then the palette (up to 8bpp), the color rows and finally the mask rows.
+    let channel_masks = match compression {
+        BI_BITFIELDS | BI_ALPHABITFIELDS if header_size < 52 => {
+            if compression == BI_ALPHABITFIELDS {
+                16
+            } else {
+                12
+            }
+        }
+        _ => 0,
+    };
+    let palette = match bpp {
+        1 | 4 | 8 if clr_used > 0 => clr_used * 4,
+        1 | 4 | 8 => (1 << bpp) * 4,
+        _ => 0,
+    };
+    let color_stride = (w * bpp).div_ceil(32) as usize * 4;
+    let mask_stride = w.div_ceil(32) as usize * 4;
+    let mask_at = header_size + channel_masks + palette + color_stride * h as usize;
+    let Some(bits) = blob.get(mask_at..mask_at + mask_stride * h as usize) else {
+        bail!(IconError::Malformed("Truncated AND mask".into()));
+    };
+    let mut mask = RgbaImage::from_pixel(w, h, Rgba([0, 0, 0, 0xFF]));
+    // Rows are stored bottom-up.
+    for (y, row) in bits.chunks(mask_stride).enumerate() {
+        for x in 0..w {
+            if row[x as usize / 8] & (0x80 >> (x % 8)) != 0 {
+                mask.put_pixel(x, h - 1 - y as u32, Rgba([0xFF, 0xFF, 0xFF, 0xFF]));
+            }
+        }
+    }
+    Ok(Some(mask))
+}
+
+// Clear alpha where the 1bpp AND mask following the color data is set. A missing or
+// truncated mask leaves the image opaque.
+fn apply_and_mask(rgba: &mut RgbaImage, mask_bytes: &[u8]) {
+    let (w, h) = rgba.dimensions();
+    let mask_stride = w.div_ceil(32) * 4;
+    if mask_bytes.len() < (mask_stride * h) as usize {
+        return;
+    }
+    for y in 0..h {
+        let src_row = (h - 1 - y) as usize;
+        let row_off = src_row * mask_stride as usize;
+        for x in 0..w {
+            let byte_index = row_off + (x / 8) as usize;
+            let bit = 7 - (x % 8);
+            if ((mask_bytes[byte_index] >> bit) & 1) == 1 {
+                rgba.get_pixel_mut(x, y).0[3] = 0;
+            }
+        }
+    }
+}
+
+// Attempt to manually decode a PNG-backed ICO entry when ico crate fails (e.g., indexed color PNG)
+// Legacy stub kept for compatibility (no longer used)
+#[allow(dead_code)]
+fn try_decode_entry_png(
+    _path: &Path,
+    _entry: &ico::IconDirEntry,
+    _debug: bool,
+) -> Result<Option<ico::IconImage>> {
+    Ok(None)
+}
+
+// Removed multi-image write helper; simplified single largest extraction.
+
+// Reassemble the first RT_GROUP_ICON of an .exe/.dll (the one Explorer shows) into an
+// in-memory ICO and extract from it like a regular icon file.
+fn extract_pe(path: &Path, out_dir: &Path, opts: &ExtractOptions) -> Result<()> {
+    let data = read_input(path)?;
+    let groups = pe::icon_groups(&data)?;
+    if opts.debug {
+        for g in &groups {
+            diag!(
+                "[debug] icon group {} ({} bytes as ICO)",
+                g.name,
+                g.ico.len()
+            );
+        }
+    }
+    let Some(group) = groups.into_iter().next() else {
+        bail!(IconError::Malformed(format!(
+            "{} contains no icon resources",
+            path.display()
+        )));
+    };
+    extract_ico_from(std::io::Cursor::new(group.ico), path, out_dir, "", opts)
+}
+
+// Animated cursors are RIFF "ACON" files: an `anih` header plus a `LIST` of type `fram`
+// holding one `icon` chunk per frame, each a complete .cur/.ico file.
+fn extract_ani(path: &Path, out_dir: &Path, opts: &ExtractOptions) -> Result<()> {
+    let data = read_input(path)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"ACON" {
+        bail!(IconError::Malformed("Not a RIFF ACON (.ani) file".into()));
+    }
+    let u32_at = |d: &[u8], off: usize| u32::from_le_bytes(d[off..off + 4].try_into().unwrap());
+    // (fourcc, body) pairs of the chunks in `d`; bodies are padded to an even length.
+    let chunks = |d: &[u8]| -> Result<Vec<([u8; 4], std::ops::Range<usize>)>> {
+        let mut out = Vec::new();
+        let mut off = 0;
+        while off + 8 <= d.len() {
+            let id: [u8; 4] = d[off..off + 4].try_into().unwrap();
+            let len = u32_at(d, off + 4) as usize;
+            let body = off + 8..off + 8 + len;
+            if body.end > d.len() {
+                bail!(IconError::Malformed(format!(
+                    "ANI chunk {} is truncated",
+                    String::from_utf8_lossy(&id)
+                )));
+            }
+            off = body.end + (len & 1);
+            out.push((id, body));
+        }
+        Ok(out)
+    };
+    let riff_end = (8 + u32_at(&data, 4) as usize).min(data.len());
+    let body = &data[12..riff_end];
+    let mut frames = Vec::new();
+    let mut raw_frames = false;
+    for (id, range) in chunks(body)? {
+        let chunk = &body[range];
+        match &id {
+            b"anih" if chunk.len() >= 36 => {
+                let flags = u32_at(chunk, 32);
+                // AF_ICON clear: frames are bare bitmaps rather than icon files.
+                raw_frames = flags & 1 == 0;
+                if opts.debug {
+                    diag!(
+                        "[debug] anih: {} frames, {} steps, {}x{} bpp={}, rate {} jiffies, flags {:#x}",
+                        u32_at(chunk, 4),
+                        u32_at(chunk, 8),
+                        u32_at(chunk, 12),
+                        u32_at(chunk, 16),
+                        u32_at(chunk, 20),
+                        u32_at(chunk, 28),
+                        flags
+                    );
+                }
+            }
+            b"LIST" if chunk.starts_with(b"fram") => {
+                for (id, range) in chunks(&chunk[4..])? {
+                    if &id == b"icon" {
+                        frames.push(&chunk[4..][range]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if raw_frames {
+        bail!(IconError::Unsupported(
+            "ANI frames stored as raw bitmaps (AF_ICON not set)".into()
+        ));
+    }
+    if frames.is_empty() {
+        bail!(IconError::Malformed("No frames in ANI file".into()));
+    }
+    for (n, frame) in frames.into_iter().enumerate() {
+        let prefix = format!("frame{}_", n);
+        extract_ico_from(std::io::Cursor::new(frame), path, out_dir, &prefix, opts)
+            .with_context(|| format!("frame {}", n))?;
+    }
+    Ok(())
+}
+
+fn extract_icns(path: &Path, out_dir: &Path, opts: &ExtractOptions) -> Result<()> {
+    if input_url(path).is_some() {
+        let data = read_input(path)?;
+        return extract_icns_from(std::io::Cursor::new(data), out_dir, opts);
+    }
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    extract_icns_from(std::io::BufReader::new(f), out_dir, opts)
+}
+
+// Only the element headers are read up front; payloads are read one at a time when
+// decoded, so large families never sit in memory whole.
+fn extract_icns_from<R: Read + Seek>(
+    mut r: R,
+    out_dir: &Path,
+    opts: &ExtractOptions,
+) -> Result<()> {
+    let entries = index_icns(&mut r)?;
+    if opts.all {
+        return extract_icns_all(&mut r, &entries, out_dir, opts);
+    }
+    // Largest declared size first; ties keep file order. Entries that fail to decode
+    // fall through to the next candidate.
+    let mut candidates: Vec<(usize, (u32, u32))> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| Some((i, icns_entry_size(e)?)))
+        .collect();
+    candidates.sort_by_key(|&(_, (w, h))| std::cmp::Reverse(w * h));
+    let mut best = None;
+    for (index, (w, h)) in candidates {
+        if opts.debug {
+            diag!(
+                "[debug] candidate {} {}x{}",
+                ostype_name(entries[index].ostype),
+                w,
+                h
+            );
+        }
+        match decode_icns_entry(&mut r, &entries, index) {
+            Ok(decoded) => {
+                best = Some((index, decoded));
+                break;
+            }
+            Err(e) if opts.debug => diag!("[debug] skipping undecodable element: {:#}", e),
+            Err(_) => {}
+        }
+    }
+    let (index, (rgba, icc_profile)) =
+        best.ok_or_else(|| IconError::Malformed("No images in ICNS".into()))?;
+    let rgba = clamp_dimension(rgba, opts);
+    prepare_out_dir(out_dir, opts)?;
+    if opts.keep_largest_only {
+        let icc_profile = icc_profile.as_deref();
+        return write_size_set(&rgba, out_dir, "", 32, index, icc_profile, opts);
+    }
+    let name = output_name(
+        opts,
+        &rgba,
+        32,
+        index,
+        format!("{}x{}.png", rgba.width(), rgba.height()),
+    );
+    write_png(&rgba, &out_dir.join(name), icc_profile.as_deref(), opts)
+}
+
+// Write every color element as `<ostype>_<w>x<h>[@2x].png` so `pack` can rebuild the same
+// family; retina elements get `@2x` so they read apart from 1x ones of the same pixel size.
+fn extract_icns_all<R: Read + Seek>(
+    r: &mut R,
+    entries: &[IcnsEntry],
+    out_dir: &Path,
+    opts: &ExtractOptions,
+) -> Result<()> {
+    prepare_out_dir(out_dir, opts)?;
+    let mut written = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let ostype = ostype_name(entry.ostype);
+        // Masks are folded into their color element; unknown types (e.g. TOC) are skipped.
+        if icns_entry_size(entry).is_none() {
+            if opts.debug {
+                diag!("[debug] skipping element {}", ostype);
+            }
+            continue;
+        }
+        let (rgba, icc_profile) =
+            decode_icns_entry(r, entries, index).with_context(|| format!("decode {}", ostype))?;
+        let rgba = clamp_dimension(rgba, opts);
+        let name = output_name(
+            opts,
+            &rgba,
+            32,
+            index,
+            format!(
+                "{}_{}x{}{}.png",
+                ostype,
+                rgba.width(),
+                rgba.height(),
+                icns_scale_suffix(entry.ostype)
+            ),
+        );
+        write_png(&rgba, &out_dir.join(name), icc_profile.as_deref(), opts)?;
+        written += 1;
+    }
+    if written == 0 {
+        bail!(IconError::Malformed("No images in ICNS".into()));
+    }
+    Ok(())
+}
+
+/// Where one element's payload sits in an .icns file.
+struct IcnsEntry {
+    ostype: icns::OSType,
+    offset: u64,
+    len: usize,
+}
+
+// Walk the element headers, seeking past each payload.
+fn index_icns<R: Read + Seek>(r: &mut R) -> Result<Vec<IcnsEntry>> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header).context("read icns header")?;
+    if &header[..4] != b"icns" {
+        bail!(IconError::Malformed("not an ICNS file".into()));
+    }
+    let total = u32::from_be_bytes(header[4..8].try_into().unwrap()) as u64;
+    let mut entries = Vec::new();
+    let mut offset = 8;
+    while offset + 8 <= total {
+        r.seek(SeekFrom::Start(offset))?;
+        r.read_exact(&mut header)
+            .with_context(|| format!("read icns element header at {}", offset))?;
+        let ostype = icns::OSType(header[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as u64;
+        if len < 8 || offset + len > total {
+            bail!(IconError::Malformed(format!(
+                "ICNS element {} at {} overruns the file",
+                ostype_name(ostype),
+                offset
+            )));
+        }
+        entries.push(IcnsEntry {
+            ostype,
+            offset: offset + 8,
+            len: (len - 8) as usize,
+        });
+        offset += len;
+    }
+    Ok(entries)
+}
+
+// `@2x` for retina element types (e.g. `ic13`, 128pt at 256px), empty otherwise.
+fn icns_scale_suffix(ostype: icns::OSType) -> &'static str {
+    match icns::IconType::from_ostype(ostype) {
+        Some(icon_type) if icon_type.pixel_density() == 2 => "@2x",
+        _ => "",
+    }
+}
+
+// Pixel size of a color element; `None` for masks and element types that hold no image.
+fn icns_entry_size(entry: &IcnsEntry) -> Option<(u32, u32)> {
+    if let Some(size) = argb_size(entry.ostype) {
+        return Some((size, size));
+    }
+    let icon_type = icns::IconType::from_ostype(entry.ostype).filter(|t| !t.is_mask())?;
+    Some((icon_type.pixel_width(), icon_type.pixel_height()))
+}
+
+fn read_icns_element<R: Read + Seek>(r: &mut R, entry: &IcnsEntry) -> Result<icns::IconElement> {
+    let mut data = vec![0u8; entry.len];
+    r.seek(SeekFrom::Start(entry.offset))?;
+    r.read_exact(&mut data)
+        .with_context(|| format!("read icns element {}", ostype_name(entry.ostype)))?;
+    Ok(icns::IconElement::new(entry.ostype, data))
+}
+
+// Read and decode `entries[index]`, plus its mask element for the legacy RLE types. PNG
+// payloads may carry an ICC profile that decoding would otherwise drop; it is returned too.
+fn decode_icns_entry<R: Read + Seek>(
+    r: &mut R,
+    entries: &[IcnsEntry],
+    index: usize,
+) -> Result<(RgbaImage, Option<Vec<u8>>)> {
+    let entry = &entries[index];
+    let element = read_icns_element(r, entry)?;
+    // Short or garbled payloads fail inside the decoders; say which element it was.
+    let corrupt = |e: anyhow::Error| {
+        IconError::Malformed(format!(
+            "ICNS element {} is truncated or corrupt: {:#}",
+            ostype_name(entry.ostype),
+            e
+        ))
+    };
+    if let Some(size) = argb_size(entry.ostype) {
+        let rgba = decode_argb_element(&element.data, size).map_err(corrupt)?;
+        return Ok((rgba, None));
+    }
+    let icon_type = element.icon_type().ok_or_else(|| {
+        IconError::Unsupported(format!("ICNS element {}", ostype_name(entry.ostype)))
+    })?;
+    let image = match icon_type.mask_type() {
+        Some(mask_type) => {
+            let mask_entry = entries
+                .iter()
+                .find(|e| e.ostype == mask_type.ostype())
+                .ok_or_else(|| {
+                    IconError::Malformed(format!(
+                        "{} has no {} mask",
+                        ostype_name(entry.ostype),
+                        ostype_name(mask_type.ostype())
+                    ))
+                })?;
+            element
+                .decode_image_with_mask(&read_icns_element(r, mask_entry)?)
+                .map_err(|e| corrupt(e.into()))?
+        }
+        None => element.decode_image().map_err(|e| corrupt(e.into()))?,
+    };
+    let icc_profile = element
+        .data
+        .starts_with(PNG_SIG)
+        .then(|| png_icc_profile(&element.data))
+        .flatten();
+    Ok((icns_to_rgba(&image, entry.ostype)?, icc_profile))
+}
+
+// The decoded pixel data must cover width x height in its format; a truncated element
+// is reported here instead of failing obscurely in the conversion below.
+fn icns_to_rgba(img: &icns::Image, ostype: icns::OSType) -> Result<RgbaImage> {
+    let expected =
+        img.width() as usize * img.height() as usize * img.pixel_format().bits_per_pixel() as usize
+            / 8;
+    if img.data().len() != expected {
+        bail!(IconError::Malformed(format!(
+            "ICNS element {} is {}x{} {:?} but holds {} bytes of pixel data, expected {}",
+            ostype_name(ostype),
+            img.width(),
+            img.height(),
+            img.pixel_format(),
+            img.data().len(),
+            expected
+        )));
+    }
+    let img = img.convert_to(icns::PixelFormat::RGBA);
+    RgbaImage::from_raw(img.width(), img.height(), img.data().to_vec())
+        .ok_or_else(|| IconError::Malformed("raw to image".into()).into())
+}
+
+fn ostype_name(ostype: icns::OSType) -> String {
+    String::from_utf8_lossy(&ostype.0).into_owned()
+}
+
+// ============ ARGB elements ============
+
+// `ic04`/`ic05` hold 16/32px icons as "ARGB" followed by the A, R, G and B planes, each
+// compressed with the same byte RLE as `it32`. The icns crate has no icon type for them.
+const ARGB_ELEMENTS: [([u8; 4], u32); 2] = [(*b"ic04", 16), (*b"ic05", 32)];
+const ARGB_MAGIC: &[u8; 4] = b"ARGB";
+
+fn argb_ostype(size: u32) -> Option<icns::OSType> {
+    ARGB_ELEMENTS
+        .iter()
+        .find(|&&(_, s)| s == size)
+        .map(|&(code, _)| icns::OSType(code))
+}
+
+fn argb_size(ostype: icns::OSType) -> Option<u32> {
+    ARGB_ELEMENTS
+        .iter()
+        .find(|&&(code, _)| code == ostype.0)
+        .map(|&(_, s)| s)
+}
+
+fn encode_argb_element(rgba: &RgbaImage) -> Vec<u8> {
+    let mut out = ARGB_MAGIC.to_vec();
+    for channel in [3, 0, 1, 2] {
+        let plane: Vec<u8> = rgba.pixels().map(|p| p.0[channel]).collect();
+        icns_rle_encode(&plane, &mut out);
+    }
+    out
+}
+
+fn decode_argb_element(data: &[u8], size: u32) -> Result<RgbaImage> {
+    let Some(packed) = data.strip_prefix(ARGB_MAGIC) else {
+        bail!(IconError::Malformed("ARGB element lacks its header".into()));
+    };
+    let len = (size * size) as usize;
+    let mut pos = 0;
+    let mut planes = Vec::with_capacity(4);
+    for _ in 0..4 {
+        planes.push(icns_rle_decode(packed, &mut pos, len)?);
+    }
+    let mut rgba = RgbaImage::new(size, size);
+    for (i, p) in rgba.pixels_mut().enumerate() {
+        *p = Rgba([planes[1][i], planes[2][i], planes[3][i], planes[0][i]]);
+    }
+    Ok(rgba)
+}
+
+// A header byte below 0x80 is followed by header+1 literal bytes; from 0x80 up, the next
+// byte repeats header-125 times (3 to 130).
+fn icns_rle_encode(plane: &[u8], out: &mut Vec<u8>) {
+    fn flush(literals: &[u8], out: &mut Vec<u8>) {
+        for chunk in literals.chunks(128) {
+            out.push(chunk.len() as u8 - 1);
+            out.extend_from_slice(chunk);
+        }
+    }
+    let (mut i, mut literal_start) = (0, 0);
+    while i < plane.len() {
+        let run = plane[i..]
+            .iter()
+            .take(130)
+            .take_while(|&&b| b == plane[i])
+            .count();
+        if run >= 3 {
+            flush(&plane[literal_start..i], out);
+            out.push(0x80 + (run - 3) as u8);
+            out.push(plane[i]);
+            literal_start = i + run;
+        }
+        i += run;
+    }
+    flush(&plane[literal_start..], out);
+}
+
+fn icns_rle_decode(data: &[u8], pos: &mut usize, len: usize) -> Result<Vec<u8>> {
+    let truncated = || IconError::Malformed("ARGB element data truncated".into());
+    let mut plane = Vec::with_capacity(len);
+    while plane.len() < len {
+        let header = *data.get(*pos).ok_or_else(truncated)? as usize;
+        *pos += 1;
+        if header < 0x80 {
+            let literals = data.get(*pos..*pos + header + 1).ok_or_else(truncated)?;
+            plane.extend_from_slice(literals);
+            *pos += header + 1;
+        } else {
+            let value = *data.get(*pos).ok_or_else(truncated)?;
+            plane.extend(std::iter::repeat_n(value, header - 125));
+            *pos += 1;
+        }
+    }
+    if plane.len() != len {
+        bail!(IconError::Malformed(
+            "ARGB channel overruns the image".into()
+        ));
+    }
+    Ok(plane)
+}
+
+// ============ Legacy mask elements ============
+
+// Pre-10.7 element pairs: RLE-packed 24-bit RGB (`it32` after four zero bytes) and an
+// uncompressed 8-bit alpha mask, one byte per pixel.
+const LEGACY_MASKED_ELEMENTS: [([u8; 4], [u8; 4], u32); 4] = [
+    (*b"is32", *b"s8mk", 16),
+    (*b"il32", *b"l8mk", 32),
+    (*b"ih32", *b"h8mk", 48),
+    (*b"it32", *b"t8mk", 128),
+];
+
+fn legacy_mask_ostypes(size: u32) -> Option<(icns::OSType, icns::OSType)> {
+    LEGACY_MASKED_ELEMENTS
+        .iter()
+        .find(|&&(_, _, s)| s == size)
+        .map(|&(color, mask, _)| (icns::OSType(color), icns::OSType(mask)))
+}
+
+fn encode_rgb24_element(rgba: &RgbaImage, ostype: icns::OSType) -> Vec<u8> {
+    let mut out = if ostype.0 == *b"it32" {
+        vec![0; 4]
+    } else {
+        Vec::new()
+    };
+    for channel in 0..3 {
+        let plane: Vec<u8> = rgba.pixels().map(|p| p.0[channel]).collect();
+        icns_rle_encode(&plane, &mut out);
+    }
+    out
+}
+
+fn encode_mask8_element(rgba: &RgbaImage) -> Vec<u8> {
+    rgba.pixels().map(|p| p.0[3]).collect()
+}
+
+// ============ Pack ============
+
+// Element type for an Apple .iconset file name (stem): `icon_<w>x<h>` in points, with an
+// `@2x` suffix for the double-density variant.
+fn iconset_icon_type(stem: &str) -> Option<icns::IconType> {
+    let rest = stem.strip_prefix("icon_")?;
+    let (dims, density) = match rest.strip_suffix("@2x") {
+        Some(dims) => (dims, 2),
+        None => (rest, 1),
+    };
+    let (w, h) = dims.split_once('x')?;
+    let (w, h): (u32, u32) = (w.parse().ok()?, h.parse().ok()?);
+    icns::IconType::from_pixel_size_and_density(w * density, h * density, density)
+}
+
+// Rebuild an ICNS from `<ostype>_<w>x<h>[@2x].png` files (as written by `extract --all`), or
+// from an Apple .iconset's `icon_<w>x<h>[@2x].png` files with `from_iconset`, storing each
+// image under its element type without any rescaling.
+fn pack_icns(dir: &Path, out: &Path, from_iconset: bool, dry_run: bool) -> Result<()> {
+    use icns::{IconElement, IconFamily, IconType, Image, OSType, PixelFormat};
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("read dir {}", dir.display()))? {
+        let p = entry?.path();
+        let is_png = p
+            .extension()
+            .and_then(|s| s.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if p.is_file() && is_png {
+            files.push(p);
+        }
+    }
+    files.sort();
+    let mut elements: Vec<(OSType, PathBuf, RgbaImage)> = Vec::new();
+    for p in files {
+        let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let code: Option<OSType> = if from_iconset {
+            iconset_icon_type(stem).map(IconType::ostype)
+        } else {
+            stem.split_once('_')
+                .and_then(|(code, _)| code.as_bytes().try_into().ok())
+                .map(OSType)
+        };
+        let known = code.map(|ostype| (ostype, argb_size(ostype), IconType::from_ostype(ostype)));
+        let (ostype, expected) = match known {
+            Some((ostype, Some(size), _)) => (ostype, (size, size)),
+            Some((_, None, Some(icon_type))) if icon_type.is_mask() => bail!(
+                "{}: mask elements are derived from the color image's alpha",
+                p.display()
+            ),
+            Some((ostype, None, Some(icon_type))) => {
+                (ostype, (icon_type.pixel_width(), icon_type.pixel_height()))
+            }
+            _ => {
+                let scheme = if from_iconset {
+                    "icon_<w>x<h>[@2x].png"
+                } else {
+                    "<ostype>_<w>x<h>.png"
+                };
+                diag!("Warning: skipping {} (not named {})", p.display(), scheme);
+                continue;
+            }
+        };
+        let rgba = load_image(&p)?.to_rgba8();
+        if rgba.dimensions() != expected {
+            bail!(
+                "{}: {} needs {}x{}, image is {}x{}",
+                p.display(),
+                ostype_name(ostype),
+                expected.0,
+                expected.1,
+                rgba.width(),
+                rgba.height()
+            );
+        }
+        elements.push((ostype, p, rgba));
+    }
+    if elements.is_empty() {
+        let scheme = if from_iconset {
+            "icon_<w>x<h>[@2x].png"
+        } else {
+            "<ostype>_<w>x<h>.png"
+        };
+        bail!("No {} files found in {}", scheme, dir.display());
+    }
+    if dry_run {
+        status!(
+            "would write {} ({} elements)",
+            out.display(),
+            elements.len()
+        );
+        for (ostype, p, _) in &elements {
+            status!("  {} <- {}", ostype_name(*ostype), p.display());
+        }
+        return Ok(());
+    }
+    let mut family = IconFamily::new();
+    for (ostype, p, rgba) in elements {
+        let Some(icon_type) = IconType::from_ostype(ostype) else {
+            family
+                .elements
+                .push(IconElement::new(ostype, encode_argb_element(&rgba)));
+            continue;
+        };
+        let (w, h) = rgba.dimensions();
+        let img = Image::from_data(PixelFormat::RGBA, w, h, rgba.into_raw())
+            .with_context(|| format!("img {}", p.display()))?;
+        family
+            .add_icon_with_type(&img, icon_type)
+            .with_context(|| format!("add {}", p.display()))?;
+    }
+    if let Some(parent) = out.parent() {
+        ensure_dir(parent)?;
+    }
+    let mut f = File::create(out).with_context(|| format!("create {}", out.display()))?;
+    family
+        .write(&mut f)
+        .with_context(|| format!("write icns {}", out.display()))
+}
+
+// Directory fields for a stored ICO payload, read from the PNG IHDR or the DIB header.
+fn raw_entry_fields(data: &[u8]) -> Option<(u32, u32, u16)> {
+    let be = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    let le = |at: usize| Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    if data.starts_with(PNG_SIG) {
+        let (depth, color_type) = (*data.get(24)? as u16, *data.get(25)?);
+        let channels = match color_type {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => return None,
+        };
+        return Some((be(16)?, be(20)?, depth * channels));
+    }
+    // The DIB height counts the color rows and the AND mask rows.
+    let header = le(0)?;
+    let bitcount = u16::from_le_bytes(data.get(14..16)?.try_into().ok()?);
+    (header >= 40).then_some((le(4)?, le(8)? / 2, bitcount))
+}
+
+// Repack the `.png`/`.bmp` payloads written by `extract --raw` into an .ico as they are,
+// without decoding or re-encoding them. Files are ordered by the directory index their
+// names start with; the directory fields are taken from each payload's header.
+fn pack_raw_entries(dir: &Path, out: &Path, dry_run: bool) -> Result<()> {
+    let mut files: Vec<(Option<usize>, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("read dir {}", dir.display()))? {
+        let p = entry?.path();
+        let ext = p
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(str::to_ascii_lowercase);
+        if !p.is_file() || !matches!(ext.as_deref(), Some("png" | "bmp")) {
+            continue;
+        }
+        let name = p.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        let digits = name.len() - name.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        files.push((name[..digits].parse().ok(), p));
+    }
+    // Unnumbered files go last, in name order.
+    files.sort_by(|a, b| (a.0.is_none(), a.0, &a.1).cmp(&(b.0.is_none(), b.0, &b.1)));
+    let mut entries = Vec::new();
+    for (_, p) in &files {
+        let data = fs::read(p).with_context(|| format!("read {}", p.display()))?;
+        let Some((width, height, bitcount)) = raw_entry_fields(&data) else {
+            bail!(IconError::Malformed(format!(
+                "{}: not a PNG or DIB icon payload",
+                p.display()
+            )));
+        };
+        if width == 0 || height == 0 || width > 256 || height > 256 {
+            bail!(
+                "{}: {}x{} doesn't fit an ICO directory entry (1-256px)",
+                p.display(),
+                width,
+                height
+            );
+        }
+        entries.push(IcoEntry {
+            width,
+            height,
+            bitcount,
+            colors: None,
+            data,
+            hotspot: None,
+        });
+    }
+    if entries.is_empty() {
+        bail!("No .png/.bmp entry files found in {}", dir.display());
+    }
+    if dry_run {
+        status!("would write {} ({} entries)", out.display(), entries.len());
+        for ((_, p), e) in files.iter().zip(&entries) {
+            status!(
+                "  {}x{} bpp={} <- {}",
+                e.width,
+                e.height,
+                e.bitcount,
+                p.display()
+            );
+        }
+        return Ok(());
+    }
+    if let Some(parent) = out.parent() {
+        ensure_dir(parent)?;
+    }
+    fs::write(out, encode_ico(&entries)).with_context(|| format!("write ico {}", out.display()))
+}
+
+// ============ Optimize ============
+
+// Read every entry of an .ico with its payload bytes, in directory order.
+fn read_ico_entries(path: &Path) -> Result<Vec<IcoEntry>> {
+    let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    if data.len() < 6 || u16::from_le_bytes([data[0], data[1]]) != 0 {
+        bail!(IconError::Malformed("Invalid ICO header".into()));
+    }
+    if u16::from_le_bytes([data[2], data[3]]) != 1 {
+        bail!(IconError::Unsupported("Not ICO".into()));
+    }
+    let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+    if data.len() < 6 + 16 * count {
+        bail!(IconError::Malformed("Truncated ICO directory".into()));
+    }
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let d = &data[6 + i * 16..6 + (i + 1) * 16];
+        let size = u32::from_le_bytes(d[8..12].try_into().unwrap()) as usize;
+        let offset = u32::from_le_bytes(d[12..16].try_into().unwrap()) as usize;
+        let Some(payload) = data.get(offset..offset.saturating_add(size)) else {
+            bail!(IconError::Malformed(format!("Entry #{} is truncated", i)));
+        };
+        entries.push(IcoEntry {
+            width: if d[0] == 0 { 256 } else { d[0] as u32 },
+            height: if d[1] == 0 { 256 } else { d[1] as u32 },
+            bitcount: u16::from_le_bytes([d[6], d[7]]),
+            colors: Some(d[2]),
+            data: payload.to_vec(),
+            hotspot: None,
+        });
+    }
+    Ok(entries)
+}
+
+// Report entries whose decoded pixels duplicate an earlier entry of the same encoding
+// (PNG vs DIB, so --dual-256 pairs survive) and, with `dedupe`, rewrite the file without them.
+// `stats` also lists each entry's distinct RGBA values and whether it is fully opaque.
+fn optimize_ico(input: &Path, output: &Path, dedupe: bool, stats: bool, debug: bool) -> Result<()> {
+    use std::collections::HashMap;
+    let original_len = fs::metadata(input)
+        .with_context(|| format!("stat {}", input.display()))?
+        .len();
+    let entries = read_ico_entries(input)?;
+    let mut seen: HashMap<(bool, u32, u32, Vec<u8>), usize> = HashMap::new();
+    let mut kept = Vec::new();
+    let mut duplicates = 0;
+    let mut stat_lines = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let rgba = match decode_ico_blob(&entry.data, debug, false) {
+            Ok(rgba) => rgba,
+            Err(e) => {
+                diag!(
+                    "Warning: entry #{} not decodable ({}), keeping it",
+                    index,
+                    e
+                );
+                kept.push(entry.clone());
+                continue;
+            }
+        };
+        if stats {
+            let colors: HashSet<[u8; 4]> = rgba.pixels().map(|p| p.0).collect();
+            let opaque = rgba.pixels().all(|p| p[3] == 0xFF);
+            stat_lines.push(format!(
+                "  #{} {}x{} bpp={} {}: {} colors, {}",
+                index,
+                rgba.width(),
+                rgba.height(),
+                entry.bitcount,
+                if entry.is_png() { "png" } else { "bmp" },
+                colors.len(),
+                if opaque { "opaque" } else { "has transparency" }
+            ));
+        }
+        let key = (entry.is_png(), rgba.width(), rgba.height(), rgba.into_raw());
+        if let Some(&first) = seen.get(&key) {
+            duplicates += 1;
+            if debug {
+                diag!(
+                    "[debug] entry #{} ({}x{} bpp={} png={}) duplicates #{}",
+                    index,
+                    entry.width,
+                    entry.height,
+                    entry.bitcount,
+                    entry.is_png(),
+                    first
+                );
+            }
+            continue;
+        }
+        seen.insert(key, index);
+        kept.push(entry.clone());
+    }
+    status!(
+        "{}: {} entries, {} duplicate(s)",
+        input.display(),
+        entries.len(),
+        duplicates
+    );
+    for line in &stat_lines {
+        status!("{}", line);
+    }
+    if !dedupe {
+        return Ok(());
+    }
+    if duplicates == 0 {
+        // Nothing to drop, but scripts still expect the output to exist.
+        let same_file = fs::canonicalize(output).ok() == fs::canonicalize(input).ok();
+        if !same_file {
+            fs::copy(input, output).with_context(|| format!("write ico {}", output.display()))?;
+            status!(
+                "wrote {} (unchanged, {} bytes)",
+                output.display(),
+                original_len
+            );
+        }
+        return Ok(());
+    }
+    let bytes = encode_ico(&kept);
+    fs::write(output, &bytes).with_context(|| format!("write ico {}", output.display()))?;
+    status!(
+        "wrote {} ({} entries, {} -> {} bytes)",
+        output.display(),
+        kept.len(),
+        original_len,
+        bytes.len()
+    );
+    Ok(())
+}
+
+// ============ Merge ============
+
+// Combine the entries of several .ico files into one, keeping each entry's payload as it
+// is. Byte-identical entries are dropped; when inputs disagree on a size, the `prefer`
+// tie-breakers pick one entry for it, and the earliest input wins a full tie.
+fn merge_icos(inputs: &[PathBuf], output: &Path, prefer: &[Prefer], dry_run: bool) -> Result<()> {
+    let mut candidates: Vec<(usize, usize, IcoEntry)> = Vec::new();
+    for (file, path) in inputs.iter().enumerate() {
+        let entries = read_ico_entries(path)?;
+        for (index, entry) in entries.into_iter().enumerate() {
+            let duplicate = candidates.iter().any(|(_, _, e)| {
+                (e.width, e.height, e.bitcount) == (entry.width, entry.height, entry.bitcount)
+                    && e.data == entry.data
+            });
+            if !duplicate {
+                candidates.push((file, index, entry));
+            }
+        }
+    }
+    let compare = |a: &IcoEntry, b: &IcoEntry| {
+        prefer.iter().fold(Ordering::Equal, |ord, p| {
+            ord.then_with(|| match p {
+                Prefer::Largest => a.data.len().cmp(&b.data.len()),
+                Prefer::Smallest => b.data.len().cmp(&a.data.len()),
+                Prefer::Png => a.is_png().cmp(&b.is_png()),
+                Prefer::Bmp => b.is_png().cmp(&a.is_png()),
+                Prefer::HighestBpp => a.bitcount.cmp(&b.bitcount),
+            })
+        })
+    };
+    let mut sizes: Vec<(u32, u32)> = candidates
+        .iter()
+        .map(|(_, _, e)| (e.width, e.height))
+        .collect();
+    sizes.sort();
+    sizes.dedup();
+    let mut merged = Vec::new();
+    for size in sizes {
+        let mut best: Option<&(usize, usize, IcoEntry)> = None;
+        let same_size = candidates
+            .iter()
+            .filter(|(_, _, e)| (e.width, e.height) == size);
+        for candidate in same_size.clone() {
+            if best.is_none_or(|b| compare(&candidate.2, &b.2) == Ordering::Greater) {
+                best = Some(candidate);
+            }
+        }
+        let (file, index, entry) = best.unwrap();
+        let dropped = same_size.count() - 1;
+        if dropped > 0 {
+            status!(
+                "{}x{}: using {} #{} ({} {}bpp, {} bytes) over {} other entr{}",
+                size.0,
+                size.1,
+                inputs[*file].display(),
+                index,
+                if entry.is_png() { "png" } else { "bmp" },
+                entry.bitcount,
+                entry.data.len(),
+                dropped,
+                if dropped == 1 { "y" } else { "ies" }
+            );
+        }
+        merged.push(entry.clone());
+    }
+    if merged.is_empty() {
+        bail!("No entries found in the inputs");
+    }
+    normalize_ico_order(&mut merged, SortOrder::Asc);
+    if dry_run {
+        status!(
+            "would write {} ({} entries)",
+            output.display(),
+            merged.len()
+        );
+        return Ok(());
+    }
+    if let Some(parent) = output.parent() {
+        ensure_dir(parent)?;
+    }
+    fs::write(output, encode_ico(&merged))
+        .with_context(|| format!("write ico {}", output.display()))?;
+    status!(
+        "wrote {} ({} entries from {} files)",
+        output.display(),
+        merged.len(),
+        inputs.len()
+    );
+    Ok(())
+}
+
+// ============ Recompress ============
+
+/// How `recompress` stores every entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum EntryEncoding {
+    /// Let the encoder choose per size, as `build` does
+    Auto,
+    /// PNG for every entry
+    Png,
+    /// 32bpp BMP/DIB for every entry
+    Bmp,
+}
+
+// Decode every entry of an .ico and re-encode it under one `encoding`, keeping the
+// directory order. Each entry's before and after byte size is reported.
+fn recompress_ico(input: &Path, output: &Path, encoding: EntryEncoding) -> Result<()> {
+    use ico::{IconDirEntry, IconImage};
+    let entries = read_ico_entries(input)?;
+    status!("{}: {} entries", input.display(), entries.len());
+    let mut recompressed = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let rgba = decode_ico_blob(&entry.data, false, false)
+            .with_context(|| format!("decode entry #{}", index))?;
+        let (w, h) = rgba.dimensions();
+        let icon = IconImage::from_rgba_data(w, h, rgba.as_raw().clone());
+        let encoded = match encoding {
+            EntryEncoding::Auto => IconDirEntry::encode(&icon),
+            EntryEncoding::Png => IconDirEntry::encode_as_png(&icon),
+            EntryEncoding::Bmp => IconDirEntry::encode_as_bmp(&icon),
+        }
+        .with_context(|| format!("encode entry #{}", index))?;
+        let mut new = IcoEntry::from_encoded(&encoded);
+        if new.is_png() && png_compression() != PngCompression::Default {
+            new.data = encode_png(&rgba, None)?;
+            new.bitcount = 32;
+        }
+        if !new.is_png() {
+            write_and_mask(&mut new.data, &rgba, DEFAULT_ALPHA_THRESHOLD);
+        }
+        status!(
+            "  #{} {}x{}: {} {}bpp {} bytes -> {} {}bpp {} bytes",
+            index,
+            w,
+            h,
+            if entry.is_png() { "png" } else { "bmp" },
+            entry.bitcount,
+            entry.data.len(),
+            if new.is_png() { "png" } else { "bmp" },
+            new.bitcount,
+            new.data.len()
+        );
+        recompressed.push(new);
+    }
+    let before: usize = entries.iter().map(|e| e.data.len()).sum();
+    let after: usize = recompressed.iter().map(|e| e.data.len()).sum();
+    if let Some(parent) = output.parent() {
+        ensure_dir(parent)?;
+    }
+    fs::write(output, encode_ico(&recompressed))
+        .with_context(|| format!("write ico {}", output.display()))?;
+    status!(
+        "wrote {} ({} entries, {} -> {} bytes of image data)",
+        output.display(),
+        recompressed.len(),
+        before,
+        after
+    );
+    Ok(())
+}
+
+// ============ Convert ============
+
+// Repack the images of an .icns as an .ico or vice versa; the output extension picks the
+// format. The output gets the target format's standard sizes up to the largest source
+// image, plus any source size the format can store. A size the input has is copied
+// as-is; any other is contain-fitted from the smallest larger image, or the largest.
+fn convert_icon(input: &Path, output: &Path) -> Result<()> {
+    let format = match input_extension(output).as_str() {
+        "ico" => TargetFormat::Ico,
+        "icns" => TargetFormat::Icns,
+        other => bail!(IconError::Unsupported(format!(
+            "conversion to .{} (write .ico or .icns)",
+            other
+        ))),
+    };
+    let mut images = decode_all_entries(input)?;
+    images.sort_by_key(|img| (img.width().min(img.height()), img.width() * img.height()));
+    let largest = images.last().map_or(0, |img| img.width().min(img.height()));
+    let mut sizes: Vec<u32> = target_sizes(format)
+        .into_iter()
+        .filter(|&s| s <= largest)
+        .collect();
+    for img in &images {
+        let s = img.width();
+        if img.height() == s && validate_size(format, s).is_ok() && !sizes.contains(&s) {
+            sizes.push(s);
+        }
+    }
+    sizes.sort();
+    if sizes.is_empty() {
+        bail!(
+            "{} has no image large enough for any {} size",
+            input.display(),
+            input_extension(output)
+        );
+    }
+    let opts = RenderOptions::contain();
+    let mut resized = Vec::new();
+    let rendered: Vec<RgbaImage> = sizes
+        .iter()
+        .map(|&s| {
+            if let Some(exact) = images.iter().find(|img| img.dimensions() == (s, s)) {
+                return exact.clone();
+            }
+            resized.push(s);
+            let base = images
+                .iter()
+                .find(|img| img.width().min(img.height()) >= s)
+                .unwrap_or(images.last().unwrap());
+            resized_rgba(&DynamicImage::ImageRgba8(base.clone()), s, &opts)
+        })
+        .collect();
+    if !resized.is_empty() {
+        diag!("Resized for missing sizes: {:?}", resized);
+    }
+    match format {
+        TargetFormat::Ico => write_ico(&sizes, rendered, &IcoOptions::default(), output)?,
+        TargetFormat::Icns => {
+            sizes = write_icns(&sizes, rendered, &IcnsOptions::default(), output)?;
+        }
+    }
+    status!(
+        "wrote {} ({} sizes: {:?})",
+        output.display(),
+        sizes.len(),
+        sizes
+    );
+    Ok(())
+}
+
+// ============ Frames ============
+
+/// Where one entry's payload sits in an .ico file.
+struct IcoDirEntry {
+    offset: u64,
+    len: usize,
+}
+
+// Read just the header and directory; payloads are read on demand by `icon_frames`.
+fn index_ico<R: Read + Seek>(r: &mut R) -> Result<Vec<IcoDirEntry>> {
+    let file_len = r.seek(SeekFrom::End(0))?;
+    let mut header = [0u8; 6];
+    r.seek(SeekFrom::Start(0))?;
+    if r.read_exact(&mut header).is_err() || u16::from_le_bytes([header[0], header[1]]) != 0 {
+        bail!(IconError::Malformed("Invalid ICO header".into()));
+    }
+    if u16::from_le_bytes([header[2], header[3]]) != 1 {
+        bail!(IconError::Unsupported("Not ICO".into()));
+    }
+    let count = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let mut dir = vec![0u8; 16 * count];
+    if r.read_exact(&mut dir).is_err() {
+        bail!(IconError::Malformed("Truncated ICO directory".into()));
+    }
+    let mut entries = Vec::with_capacity(count);
+    for (i, d) in dir.chunks_exact(16).enumerate() {
+        let len = u32::from_le_bytes(d[8..12].try_into().unwrap());
+        let offset = u32::from_le_bytes(d[12..16].try_into().unwrap()) as u64;
+        // Checked here so a bogus size never turns into a huge allocation later.
+        if offset + len as u64 > file_len {
+            bail!(IconError::Malformed(format!("Entry #{} is truncated", i)));
+        }
+        entries.push(IcoDirEntry {
+            offset,
+            len: len as usize,
+        });
+    }
+    Ok(entries)
+}
+
+/// Lazily decoded (width, height, pixels) of each image in an icon file.
+type IconFrames = Box<dyn Iterator<Item = Result<(u32, u32, RgbaImage)>>>;
+
+// Every image in an .ico/.icns as (width, height, pixels), in file order. Only the
+// directory is read up front; each entry is read and decoded when the iterator reaches it,
+// so a caller that stops early (say, at the first size >= 64) never decodes the rest.
+// ICNS masks are folded into their color elements as in `extract --all`.
+fn icon_frames(path: &Path) -> Result<IconFrames> {
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut r = std::io::BufReader::new(f);
+    let frame = |rgba: RgbaImage| (rgba.width(), rgba.height(), rgba);
+    if input_extension(path) == "icns" {
+        let entries = index_icns(&mut r)?;
+        let images: Vec<usize> = (0..entries.len())
+            .filter(|&i| icns_entry_size(&entries[i]).is_some())
+            .collect();
+        return Ok(Box::new(images.into_iter().map(move |index| {
+            let (rgba, _) = decode_icns_entry(&mut r, &entries, index)
+                .with_context(|| format!("decode {}", ostype_name(entries[index].ostype)))?;
+            Ok(frame(rgba))
+        })));
+    }
+    let entries = index_ico(&mut r)?;
+    Ok(Box::new(entries.into_iter().enumerate().map(
+        move |(index, entry)| {
+            let mut data = vec![0u8; entry.len];
+            r.seek(SeekFrom::Start(entry.offset))?;
+            r.read_exact(&mut data)
+                .with_context(|| format!("read entry #{}", index))?;
+            let rgba = decode_ico_blob(&data, false, false)
+                .with_context(|| format!("decode entry #{}", index))?;
+            Ok(frame(rgba))
+        },
+    )))
+}
+
+// ============ Preview ============
+
+// Every image in an .ico/.icns, decoded, in file order.
+fn decode_all_entries(path: &Path) -> Result<Vec<RgbaImage>> {
+    let images = icon_frames(path)?
+        .map(|frame| frame.map(|(_, _, rgba)| rgba))
+        .collect::<Result<Vec<_>>>()?;
+    if images.is_empty() {
+        bail!(IconError::Malformed(format!(
+            "No images in {}",
+            path.display()
+        )));
+    }
+    Ok(images)
+}
+
+// Write an endlessly looping APNG that shows each image, smallest first, centered at its
+// native size on a transparent canvas as large as the biggest one.
+fn write_size_animation(mut frames: Vec<RgbaImage>, out: &Path, frame_ms: u16) -> Result<()> {
+    frames.sort_by_key(|f| f.width() * f.height());
+    let width = frames.iter().map(|f| f.width()).max().unwrap_or(1);
+    let height = frames.iter().map(|f| f.height()).max().unwrap_or(1);
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(frame_ms, 1000)?;
+    let mut writer = encoder.write_header()?;
+    for frame in &frames {
+        let mut canvas = RgbaImage::new(width, height);
+        let x = (width - frame.width()) / 2;
+        let y = (height - frame.height()) / 2;
+        image::imageops::replace(&mut canvas, frame, x as i64, y as i64);
+        writer.write_image_data(canvas.as_raw())?;
+    }
+    writer.finish()?;
+    fs::write(out, &buf).with_context(|| format!("write {}", out.display()))?;
+    let sizes: Vec<String> = frames
+        .iter()
+        .map(|f| format!("{}x{}", f.width(), f.height()))
+        .collect();
+    status!(
+        "wrote {} ({} frames: {})",
+        out.display(),
+        frames.len(),
+        sizes.join(", ")
+    );
+    Ok(())
+}
+
+fn preview_animation(input: &Path, out: &Path, frame_ms: u16) -> Result<()> {
+    match input_extension(out).as_str() {
+        "png" | "apng" => {}
+        "webp" => bail!(IconError::Unsupported(
+            "animated WebP output; write .png or .apng instead".into()
+        )),
+        other => bail!(IconError::Unsupported(format!(
+            "animation output .{} (use .png or .apng)",
+            other
+        ))),
+    }
+    let frames = decode_all_entries(input)?;
+    write_size_animation(frames, out, frame_ms)
+}
+
+// ============ Info ============
+
+// List an .ico's directory entries. `compat_check` also rates each one for shells that
+// predate PNG-compressed entries (Windows XP and older, some legacy tools): a size stored
+// only as PNG shows blank there unless a BMP entry of the same size is present.
+fn ico_info(path: &Path, compat_check: bool) -> Result<()> {
+    let entries = read_ico_entries(path)?;
+    status!("{}: {} entries", path.display(), entries.len());
+    let has_bmp = |w: u32, h: u32| {
+        entries
+            .iter()
+            .any(|e| !e.is_png() && e.width == w && e.height == h)
+    };
+    let mut png_only: Vec<u32> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let encoding = if entry.is_png() { "png" } else { "bmp" };
+        let verdict = if !compat_check {
+            String::new()
+        } else if !entry.is_png() {
+            "  ok".to_string()
+        } else if has_bmp(entry.width, entry.height) {
+            "  ok (BMP fallback present)".to_string()
+        } else {
+            png_only.push(entry.width);
+            "  PNG only: blank on pre-Vista shells".to_string()
+        };
+        status!(
+            "  #{} {}x{} bpp={} {} {} bytes{}",
+            index,
+            entry.width,
+            entry.height,
+            entry.bitcount,
+            encoding,
+            entry.data.len(),
+            verdict
+        );
+    }
+    if !compat_check {
+        return Ok(());
+    }
+    png_only.sort_unstable();
+    png_only.dedup();
+    if png_only.is_empty() {
+        status!("compatibility: every size has a BMP entry; readable by all Windows versions");
+        return Ok(());
+    }
+    status!(
+        "compatibility: needs Windows Vista or later ({} size(s) PNG only: {:?})",
+        png_only.len(),
+        png_only
+    );
+    if png_only.iter().any(|&s| s < 256) {
+        diag!("Warning: rebuild with --include-256-png-only to store sizes below 256px as BMP");
+    }
+    if png_only.contains(&256) {
+        diag!("Warning: rebuild with --dual-256 to add a BMP fallback for 256px");
+    }
+    Ok(())
+}
+
+// ============ Validate ============
+
+/// Platform whose icon requirements `validate` checks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum PlatformSpec {
+    /// Windows application icon: .ico with 16, 32, 48 and 256px (warns on PNG-only small sizes)
+    WindowsApp,
+    /// macOS application icon: .icns with every size from 16 to 1024px
+    MacosApp,
+    /// Website favicon: .ico with 16 and 32px
+    Favicon,
+}
+
+impl PlatformSpec {
+    fn format(self) -> TargetFormat {
+        match self {
+            PlatformSpec::WindowsApp | PlatformSpec::Favicon => TargetFormat::Ico,
+            PlatformSpec::MacosApp => TargetFormat::Icns,
+        }
+    }
+
+    fn required_sizes(self) -> Vec<u32> {
+        match self {
+            PlatformSpec::WindowsApp => vec![16, 32, 48, 256],
+            PlatformSpec::MacosApp => icns_sizes(),
+            PlatformSpec::Favicon => vec![16, 32],
+        }
+    }
+
+    // Sizes below this should also have a BMP entry for Windows XP and older, which can't
+    // draw PNG entries. Only a warning, as `info --compat-check` treats it: Vista and later
+    // read PNG at every size, and `build` stores small sizes as PNG when that's smaller.
+    fn bmp_below(self) -> Option<u32> {
+        match self {
+            PlatformSpec::WindowsApp => Some(256),
+            PlatformSpec::MacosApp | PlatformSpec::Favicon => None,
+        }
+    }
+}
+
+// Check an .ico/.icns against a platform's baked-in requirements, printing pass or FAIL
+// for each one; fails when any requirement is not met.
+fn validate_icon(path: &Path, spec: PlatformSpec) -> Result<()> {
+    let format = spec.format();
+    let spec_name = spec.to_possible_value().unwrap().get_name().to_string();
+    let extension = match format {
+        TargetFormat::Ico => "ico",
+        TargetFormat::Icns => "icns",
+    };
+    // Each requirement with the reason it failed, if it did.
+    let mut results: Vec<(String, Option<String>)> = Vec::new();
+    // Compatibility advice that doesn't fail the check.
+    let mut warnings: Vec<String> = Vec::new();
+    let found_extension = input_extension(path);
+    results.push((
+        format!(".{} file", extension),
+        (found_extension != extension).then(|| format!("found .{}", found_extension)),
+    ));
+    if found_extension == extension {
+        let decoded = decoded_sizes(path, format);
+        results.push((
+            "every image decodes at its declared size".into(),
+            decoded.as_ref().err().map(|e| format!("{:#}", e)),
+        ));
+        let found = decoded.unwrap_or_default();
+        // A directory that can't be read has already failed the decode check.
+        let entries = match format {
+            TargetFormat::Ico => read_ico_entries(path).unwrap_or_default(),
+            TargetFormat::Icns => Vec::new(),
+        };
+        let nonsquare: Vec<String> = entries
+            .iter()
+            .filter(|e| e.width != e.height)
+            .map(|e| format!("{}x{}", e.width, e.height))
+            .collect();
+        if matches!(format, TargetFormat::Ico) {
+            results.push((
+                "every entry is square".into(),
+                (!nonsquare.is_empty()).then(|| nonsquare.join(", ")),
+            ));
+        }
+        for s in spec.required_sizes() {
+            let present = found.contains(&s);
+            results.push((
+                format!("{}x{} present", s, s),
+                (!present).then(|| "missing".into()),
+            ));
+            if present && spec.bmp_below().is_some_and(|limit| s < limit) {
+                let has_bmp = entries
+                    .iter()
+                    .any(|e| !e.is_png() && e.width == s && e.height == s);
+                if !has_bmp {
+                    warnings.push(format!(
+                        "{}x{} is PNG only; Windows XP and older show it blank",
+                        s, s
+                    ));
+                }
+            }
+        }
+    }
+    status!("{}: {}", path.display(), spec_name);
+    for (requirement, failure) in &results {
+        match failure {
+            None => status!("  pass  {}", requirement),
+            Some(why) => status!("  FAIL  {} ({})", requirement, why),
+        }
+    }
+    for warning in &warnings {
+        status!("  warn  {}", warning);
+    }
+    let failed = results.iter().filter(|(_, f)| f.is_some()).count();
+    if failed > 0 {
+        bail!(
+            "{} fails {} of {} {} requirements",
+            path.display(),
+            failed,
+            results.len(),
+            spec_name
+        );
+    }
+    Ok(())
+}
+
+// ============ Hash ============
+
+// SHA-256 over the decoded images rather than the file bytes, so re-encoding (BMP vs PNG,
+// compression level, entry order, a duplicated --dual-256 entry) doesn't change it. Each
+// distinct image contributes its width and height (u32 big-endian) and RGBA rows, sorted
+// by size and then pixels; fully transparent pixels count as 0,0,0,0 since encoders
+// disagree on the color hidden under them.
+fn content_hash(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut images: Vec<(u32, u32, Vec<u8>)> = decode_all_entries(path)?
+        .into_iter()
+        .map(|mut rgba| {
+            for p in rgba.pixels_mut() {
+                if p[3] == 0 {
+                    *p = Rgba([0, 0, 0, 0]);
+                }
+            }
+            (rgba.width(), rgba.height(), rgba.into_raw())
+        })
+        .collect();
+    images.sort();
+    images.dedup();
+    let mut hasher = Sha256::new();
+    for (w, h, pixels) in &images {
+        hasher.update(w.to_be_bytes());
+        hasher.update(h.to_be_bytes());
+        hasher.update(pixels);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+// ============ Embed ============
+
+// Stamp `ico` into a Windows executable as its application icon.
+fn embed_ico(exe: &Path, ico: &Path, output: &Path) -> Result<()> {
+    let data = fs::read(exe).with_context(|| format!("read {}", exe.display()))?;
+    let icon = fs::read(ico).with_context(|| format!("read {}", ico.display()))?;
+    let embedded = pe::embed_icon(&data, &icon)?;
+    if embedded.stripped_signature {
+        diag!(
+            "Warning: {} was signed; the signature no longer matches and was removed",
+            exe.display()
+        );
+    }
+    fs::write(output, &embedded.image).with_context(|| format!("write {}", output.display()))?;
+    status!(
+        "wrote {} (icon group {}, {} -> {} bytes)",
+        output.display(),
+        embedded.group,
+        data.len(),
+        embedded.image.len()
+    );
+    Ok(())
+}
+
+// Path of `target` relative to the directory `base`, both resolved to absolute paths.
+fn relative_path(base: &Path, target: &Path) -> Result<PathBuf> {
+    let base = fs::canonicalize(base).with_context(|| format!("resolve {}", base.display()))?;
+    let target =
+        fs::canonicalize(target).with_context(|| format!("resolve {}", target.display()))?;
+    let common = base
+        .components()
+        .zip(target.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut rel: PathBuf = base.components().skip(common).map(|_| "..").collect();
+    rel.extend(target.components().skip(common));
+    Ok(rel)
+}
+
+// Write a Rust source file declaring `pub const ICON: &[u8]` over the built icon. The bytes
+// stay in `icon` and are pulled in with include_bytes!, whose path is relative to `rs`.
+fn write_rust_include(icon: &Path, rs: &Path) -> Result<()> {
+    let dir = rs
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    ensure_dir(dir)?;
+    // Forward slashes work in include_bytes! on every host, backslashes only on Windows.
+    let rel = relative_path(dir, icon)?
+        .to_string_lossy()
+        .replace('\\', "/");
+    let line = format!("pub const ICON: &[u8] = include_bytes!({:?});", rel);
+    let file_name = icon.file_name().unwrap_or_default().to_string_lossy();
+    let source = format!(
+        "/// Bytes of `{}`, generated by icon-rust.\n{}\n",
+        file_name, line
+    );
+    fs::write(rs, source).with_context(|| format!("write {}", rs.display()))?;
+    status!("wrote {}", rs.display());
+    status!("{}", line);
+    Ok(())
 }
 
 // ============ CLI ============
@@ -384,30 +5481,593 @@ enum TargetFormat {
     Icns,
 }
 
-#[derive(Subcommand, Debug)]
+/// `build` defaults read from a `--config` TOML file; flags given on the command line win.
+#[derive(Debug, Default)]
+struct BuildConfig {
+    format: Option<TargetFormat>,
+    sizes: Option<Vec<u32>>,
+    fit: Option<Fit>,
+    filter: Option<ResizeFilter>,
+    /// Contain-fit padding color, as `--letterbox`.
+    background: Option<Rgba<u8>>,
+}
+
+fn load_build_config(path: &Path) -> Result<BuildConfig> {
+    let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("parse {}", path.display()))?;
+    let mut config = BuildConfig::default();
+    for (key, value) in &table {
+        let text = || {
+            value
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("expected a string, found {}", value.type_str()))
+        };
+        let parsed: Result<()> = (|| {
+            match key.as_str() {
+                "format" => {
+                    config.format =
+                        Some(TargetFormat::from_str(text()?, true).map_err(anyhow::Error::msg)?)
+                }
+                "sizes" => {
+                    let list = value.as_array().ok_or_else(|| {
+                        anyhow::anyhow!("expected an array, found {}", value.type_str())
+                    })?;
+                    let sizes = list
+                        .iter()
+                        .map(|v| {
+                            v.as_integer()
+                                .and_then(|n| u32::try_from(n).ok())
+                                .ok_or_else(|| anyhow::anyhow!("{} is not a pixel size", v))
+                        })
+                        .collect::<Result<Vec<u32>>>()?;
+                    config.sizes = Some(sizes);
+                }
+                "fit" => {
+                    config.fit = Some(Fit::from_str(text()?, true).map_err(anyhow::Error::msg)?)
+                }
+                "filter" => {
+                    config.filter =
+                        Some(ResizeFilter::from_str(text()?, true).map_err(anyhow::Error::msg)?)
+                }
+                "background" => {
+                    config.background = Some(parse_hex_color(text()?).map_err(anyhow::Error::msg)?)
+                }
+                _ => bail!("unknown key (expected format, sizes, fit, filter or background)"),
+            }
+            Ok(())
+        })();
+        parsed.with_context(|| format!("{}: `{}`", path.display(), key))?;
+    }
+    Ok(config)
+}
+
+/// Size selection shared by the build commands.
+#[derive(Args, Clone, Debug)]
+struct SizeArgs {
+    /// Comma-separated sizes to generate instead of the format's default set
+    #[clap(long, value_delimiter = ',')]
+    sizes: Option<Vec<u32>>,
+    /// Drop sizes below N px from the size list
+    #[clap(long, value_name = "N")]
+    min_size: Option<u32>,
+    /// Drop sizes above N px from the size list
+    #[clap(long, value_name = "N")]
+    max_size: Option<u32>,
+    /// Build exactly one size, N px (shorthand for --sizes N)
+    #[clap(long, value_name = "N", conflicts_with_all = ["sizes", "min_size", "max_size"])]
+    only_size: Option<u32>,
+    /// Always include 16, 24, 32, 48, 64, 96, 128 and 256 (the sizes Windows Explorer requests)
+    #[clap(long, conflicts_with = "only_size")]
+    windows_complete: bool,
+}
+
+impl SizeArgs {
+    // Explicit or default sizes for the format, deduplicated, then narrowed by min/max.
+    fn resolve(&self, format: TargetFormat) -> Result<Vec<u32>> {
+        if let Some(size) = self.only_size {
+            validate_size(format, size)?;
+            return Ok(vec![size]);
+        }
+        let mut sizes = match &self.sizes {
+            Some(list) => {
+                for &s in list {
+                    validate_size(format, s)?;
+                }
+                list.clone()
+            }
+            None => target_sizes(format),
+        };
+        sizes.sort_unstable();
+        sizes.dedup();
+        let all = sizes.clone();
+        let min = self.min_size.unwrap_or(0);
+        let max = self.max_size.unwrap_or(u32::MAX);
+        sizes.retain(|&s| s >= min && s <= max);
+        if self.windows_complete {
+            if matches!(format, TargetFormat::Icns) {
+                bail!("--windows-complete only applies to ICO output");
+            }
+            sizes.extend_from_slice(WINDOWS_COMPLETE_SIZES);
+            sizes.sort_unstable();
+            sizes.dedup();
+        }
+        if sizes.is_empty() {
+            bail!(
+                "--min-size/--max-size left no sizes to build (candidates: {:?})",
+                all
+            );
+        }
+        Ok(sizes)
+    }
+}
+
+// Parsed once per run, so the size of the Build variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Clone, Debug)]
 enum Commands {
     /// Extract all frames/images from an .ico or .icns into PNG files
     Extract {
         input: PathBuf,
         out_dir: PathBuf,
         #[clap(long)]
-        debug: bool,
+        debug: bool,
+        /// Decode and select as usual but only print what would be written
+        #[clap(long)]
+        dry_run: bool,
+        /// Tie-break policy for equally sized ICO entries, applied in order
+        #[clap(long, value_enum, value_delimiter = ',', default_values_t = [Prefer::HighestBpp, Prefer::Largest])]
+        prefer: Vec<Prefer>,
+        /// Fail instead of warning when an ICO entry's declared size disagrees with its data
+        #[clap(long)]
+        strict: bool,
+        /// Extract every entry instead of only the largest (ICNS files are named <ostype>_<w>x<h>[@2x].png)
+        #[clap(long)]
+        all: bool,
+        /// Write the largest image at each standard size 16-512 it covers, as <s>x<s>.png
+        #[clap(long, conflicts_with = "all")]
+        keep_largest_only: bool,
+        /// How --keep-largest-only fits the image into each size (default contain); both
+        /// writes <s>x<s>_contain.png and <s>x<s>_cover.png
+        #[clap(long, value_enum, requires = "keep_largest_only")]
+        fit: Option<Fit>,
+        /// Don't rewrite output files whose contents would be identical (keeps mtimes stable)
+        #[clap(long)]
+        skip_unchanged: bool,
+        /// Downscale extracted images so neither side exceeds N pixels (names follow the new size)
+        #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        max_dimension: Option<u32>,
+        /// Save opaque grayscale or <=256-color images as grayscale/palette PNGs instead of RGBA
+        #[clap(long)]
+        preserve_color_type: bool,
+        /// Name output files from a pattern with {w}, {h}, {bpp} and {index}, e.g. "app_icon_{w}.png"
+        #[clap(long, value_name = "TEMPLATE", value_parser = parse_output_template)]
+        output_template: Option<OutputTemplate>,
+        /// Warn when semi-transparent edges look premultiplied or matted (dark/light fringes)
+        #[clap(long)]
+        pre_multiply_check: bool,
+        /// Dump each DIB entry's header fields, strides and offsets while decoding it
+        #[clap(long)]
+        verbose_dib: bool,
+        /// ICO/CUR only: list the directory entries with their indices (extracts only with --index)
+        #[clap(long, conflicts_with = "all")]
+        list_then_select: bool,
+        /// ICO/CUR only: extract directory entry N as listed by --list-then-select
+        #[clap(long, value_name = "N", conflicts_with = "all")]
+        index: Option<usize>,
+        /// ICO/CUR only: skip entries that can't be read or decoded; without --all, fall back
+        /// to the next-best entry
+        #[clap(long)]
+        tolerant: bool,
+        /// ICO/CUR only: write each entry's stored PNG/DIB bytes unchanged, as <index>_<w>x<h>.png|.bmp
+        #[clap(long, conflicts_with_all = ["keep_largest_only", "max_dimension", "preserve_color_type", "output_template", "pre_multiply_check"])]
+        raw: bool,
+        /// ICO/CUR only: also write each BMP entry's 1-bit AND mask as <name>_mask.png
+        /// (white = transparent)
+        #[clap(long, conflicts_with_all = ["raw", "keep_largest_only"])]
+        mask: bool,
+        /// Background for transparent pixels when writing a format without alpha (.jpg)
+        #[clap(long, value_name = "RRGGBB", default_value = "FFFFFF", value_parser = parse_hex_color)]
+        matte: Rgba<u8>,
     },
     /// Build icon (.ico/.icns) from a single base image (auto-resize)
+    #[command(allow_missing_positional = true)]
     Build {
         input: PathBuf,
+        /// Output format; may be left out when --config sets one
         #[clap(value_enum)]
-        format: TargetFormat,
+        format: Option<TargetFormat>,
         output: PathBuf,
-        #[clap(long, default_value_t = true)]
-        contain: bool,
+        /// Read defaults for format, sizes, fit, filter and background from a TOML file
+        #[clap(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+        /// Build from frame N (0-based) of an animated GIF/PNG or page N of a multi-page TIFF
+        #[clap(long, value_name = "N")]
+        frame: Option<usize>,
+        /// Mirror the source before building: h (left-right) or v (top-bottom)
+        #[clap(long, value_enum)]
+        flip: Option<Flip>,
+        /// Rotate the source clockwise by 90, 180 or 270 degrees before building (after --flip)
+        #[clap(long, value_name = "DEGREES", value_parser = parse_rotation)]
+        rotate: Option<u32>,
+        /// Contain-fit (true, default) or cover-fit (false) the source into each square
+        #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+        contain: Option<bool>,
+        /// How to fit the source into each square; auto picks contain for sources with
+        /// transparency and cover for opaque ones
+        #[clap(long, value_enum, conflicts_with = "contain")]
+        fit: Option<Fit>,
+        /// Print which fit --fit auto chose and why
+        #[clap(long)]
+        debug: bool,
+        /// Bake a drop shadow beneath the artwork: "dx,dy,blur,RRGGBBAA" (pixels at 256px)
+        #[clap(long, value_parser = parse_shadow)]
+        shadow: Option<Shadow>,
+        /// Composite the artwork over this image, cover-fitted to every size
+        #[clap(long, value_name = "PATH")]
+        background_image: Option<PathBuf>,
+        /// Composite the artwork over a linear gradient: "RRGGBB,RRGGBB,angle" (degrees, CSS-style)
+        #[clap(long, value_name = "FROM,TO,ANGLE", value_parser = parse_gradient, conflicts_with_all = ["background_image", "letterbox", "flatten_against"])]
+        background_gradient: Option<Gradient>,
+        /// Fill the contain-fit padding with this color (RRGGBB[AA]); artwork alpha is kept
+        #[clap(long, value_parser = parse_hex_color)]
+        letterbox: Option<Rgba<u8>>,
+        /// Make every size fully opaque: composite the source over this color (RRGGBB) before
+        /// resizing and fill any padding with it
+        #[clap(long, value_name = "RRGGBB", value_parser = parse_hex_color, conflicts_with_all = ["letterbox", "mask_from"])]
+        flatten_against: Option<Rgba<u8>>,
+        /// Multiply each size's alpha by this image's grayscale and alpha (resized to match)
+        #[clap(long, value_name = "PATH")]
+        mask_from: Option<PathBuf>,
+        /// Extend edge colors into fully transparent pixels to avoid dark halos when scaled
+        #[clap(long)]
+        alpha_bleed: bool,
+        /// Crop the source to a square once before resizing, so every size shares one framing
+        #[clap(long, value_enum)]
+        square: Option<SquareCrop>,
+        /// Pad the source to a centered square once before resizing, transparent or RRGGBB[AA]
+        #[clap(long, value_name = "RRGGBB", value_parser = parse_hex_color, num_args = 0..=1, default_missing_value = "00000000", conflicts_with = "square")]
+        pre_square: Option<Rgba<u8>>,
+        /// ICO only: keep the source's aspect and store each size as the fitted rectangle
+        /// (e.g. 256x128) instead of padding or cropping it to a square
+        #[clap(long, conflicts_with_all = ["contain", "fit", "square", "pre_square", "shadow", "background_image", "background_gradient", "letterbox", "mask_from", "label"])]
+        allow_nonsquare: bool,
+        /// Draw TEXT on a ribbon across one corner of every size (e.g. "BETA")
+        #[clap(long, value_name = "TEXT", requires = "label_font")]
+        label: Option<String>,
+        /// TrueType/OpenType font for --label
+        #[clap(long, value_name = "PATH", requires = "label")]
+        label_font: Option<PathBuf>,
+        /// Corner the --label ribbon crosses
+        #[clap(long, value_enum, default_value_t = LabelCorner::TopRight)]
+        label_corner: LabelCorner,
+        /// Ribbon color for --label (RRGGBB[AA]); the text is white or black, whichever reads
+        #[clap(long, value_name = "RRGGBB", value_parser = parse_hex_color, default_value = "D32F2F")]
+        label_color: Rgba<u8>,
+        /// Resampling filter for every size (default lanczos3)
+        #[clap(long, value_enum)]
+        filter: Option<ResizeFilter>,
+        /// Choose the filter per size: box (area average) up to 32px, lanczos3 above
+        #[clap(long)]
+        auto_filter: bool,
+        /// Scale pixel art by nearest neighbour to sizes that are a whole multiple of the source
+        /// (e.g. 16 -> 32, 64); other sizes use the chosen filter
+        #[clap(long)]
+        pixel_art: bool,
+        /// Per-size filter overrides, e.g. "16:nearest,1024:lanczos3" (others use --filter)
+        #[clap(long, value_name = "SIZE:FILTER", value_delimiter = ',', value_parser = parse_size_rule)]
+        size_rule: Vec<SizeRule>,
+        /// Render SIZE from this file instead of the input, e.g. "16=tiny.png" (repeatable)
+        #[clap(long, value_name = "SIZE=PATH", value_parser = parse_size_source)]
+        size_src: Vec<SizeSource>,
+        /// Downscale large sources to fit N px once, then derive every size from that
+        #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        base_size: Option<u32>,
+        /// Warn when the source's long side exceeds its short side by more than RATIO (e.g. 1.25)
+        #[clap(long, value_name = "RATIO", value_parser = parse_aspect_ratio)]
+        warn_aspect: Option<f32>,
+        /// Make --warn-aspect an error instead of a warning
+        #[clap(long)]
+        strict: bool,
+        /// ICNS only: leave out sizes that fail to encode and list them, instead of aborting
+        #[clap(long)]
+        strip_incomplete: bool,
+        /// ICNS only: store 16 and 32px as ARGB elements (ic04/ic05) instead of PNG
+        #[clap(long)]
+        argb_small: bool,
+        /// ICNS only: add classic RGB elements with 8-bit masks (is32/s8mk, ...) for pre-10.7 macOS
+        #[clap(long)]
+        legacy_masks: bool,
+        /// Re-read the output and check that every size is present and decodes
+        #[clap(long)]
+        verify_after_write: bool,
+        /// Delete the output when --verify-after-write finds a problem
+        #[clap(long, requires = "verify_after_write")]
+        delete_invalid: bool,
+        /// ICO only: store 256px both as an uncompressed BMP and as PNG
+        #[clap(long)]
+        dual_256: bool,
+        /// ICO only: embed an ICC profile in PNG entries: "srgb" (built-in) or a .icc path
+        #[clap(long, value_name = "srgb|PATH")]
+        icc_profile: Option<String>,
+        /// ICO only: alpha below N is transparent in the 1-bit AND mask of BMP entries
+        #[clap(long, value_name = "N", default_value_t = DEFAULT_ALPHA_THRESHOLD)]
+        alpha_threshold: u8,
+        /// ICO only: store sizes below N (default 256) as BMP and sizes from N up as PNG
+        #[clap(long, value_name = "N", num_args = 0..=1, default_missing_value = "256")]
+        include_256_png_only: Option<u32>,
+        /// ICO only: directory order of the sizes
+        #[clap(long, value_enum, default_value_t = SortOrder::Asc)]
+        sort_sizes: SortOrder,
+        /// ICO only: also store each size as a 1bpp black-and-white entry; luminance from N
+        /// (default 128) up is white, and the mask follows --alpha-threshold
+        #[clap(long, value_name = "N", num_args = 0..=1, default_missing_value = "128")]
+        monochrome: Option<u8>,
+        #[command(flatten)]
+        sizes: SizeArgs,
+        /// Print the sizes, sources and output path without writing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Report how long loading, each size's resize and encode, and writing took
+        #[clap(long)]
+        time: bool,
+        /// Print a summary of the written icon: source, fit, and each image's encoding, bytes and filter
+        #[clap(long)]
+        report: bool,
+        /// Also write the --report summary as JSON to PATH
+        #[clap(long, value_name = "PATH")]
+        report_json: Option<PathBuf>,
+        /// Also write a .rs file declaring `pub const ICON: &[u8]` via include_bytes! of the output
+        #[clap(long, value_name = "PATH")]
+        emit_rust: Option<PathBuf>,
+        /// Reuse the output cached here when the source and options are unchanged, and cache
+        /// fresh builds
+        #[clap(long, value_name = "DIR", conflicts_with_all = ["report", "report_json", "verify_after_write", "time"])]
+        cache_dir: Option<PathBuf>,
+        /// Fail without writing if the output would be larger than KB kilobytes (1 KB = 1024 bytes)
+        #[clap(long, value_name = "KB", value_parser = clap::value_parser!(u64).range(1..))]
+        max_file_size: Option<u64>,
+        /// INPUT is a directory written by `extract --raw`; pack its entries into an .ico as
+        /// they are, without decoding or re-encoding
+        #[clap(long)]
+        from_raw_entries: bool,
     },
     /// Build from a directory of images (largest used as base)
     BuildDir {
+        /// Directory to scan, or a quoted glob pattern such as "assets/*-icon-*.png"
         dir: PathBuf,
         #[clap(value_enum)]
         format: TargetFormat,
         output: PathBuf,
+        #[command(flatten)]
+        sizes: SizeArgs,
+        /// Fail, listing the sizes, unless every target size has a source file named for it
+        #[clap(long)]
+        exact_size_required: bool,
+        /// Print the sizes, sources and output path without writing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Write an iOS AppIcon.appiconset (PNGs + Contents.json) from one image
+    ///
+    /// Generated icons, as points@scale = pixels: 20@1x=20, 20@2x=40, 20@3x=60,
+    /// 29@1x=29, 29@2x=58, 29@3x=87, 40@1x=40, 40@2x=80, 40@3x=120, 60@2x=120,
+    /// 60@3x=180, 76@1x=76, 76@2x=152, 83.5@2x=167 and 1024@1x=1024 (App Store).
+    /// Files are named Icon-App-<pt>x<pt>@<scale>x.png. Images are cover-fitted
+    /// and flattened onto --background because iOS icons must be opaque.
+    AppleTouch {
+        input: PathBuf,
+        out_dir: PathBuf,
+        /// Opaque color (RRGGBB) placed behind transparent areas
+        #[clap(long, value_parser = parse_hex_color, default_value = "FFFFFF")]
+        background: Rgba<u8>,
+        /// Embed an ICC profile in PNG output: "srgb" for the built-in profile or a .icc path
+        #[clap(long, value_name = "srgb|PATH")]
+        icc_profile: Option<String>,
+    },
+    /// Write favicon.ico (16, 32, 48) plus icon-192.png and icon-512.png for web apps
+    Favicon {
+        input: PathBuf,
+        out_dir: PathBuf,
+        /// Also write a web app manifest snippet with an "icons" array for these files
+        #[clap(long, value_name = "PATH")]
+        manifest_json: Option<PathBuf>,
+        /// Prepended to each "src" in the manifest snippet, e.g. "/static/icons/"
+        #[clap(
+            long,
+            value_name = "PREFIX",
+            default_value = "",
+            requires = "manifest_json"
+        )]
+        src_prefix: String,
+        /// Embed an ICC profile in PNG output: "srgb" for the built-in profile or a .icc path
+        #[clap(long, value_name = "srgb|PATH")]
+        icc_profile: Option<String>,
+    },
+    /// Write Android launcher icons into mipmap-<density> folders of a res/ directory
+    ///
+    /// Legacy icons: mdpi 48, hdpi 72, xhdpi 96, xxhdpi 144, xxxhdpi 192 px.
+    /// With --adaptive, 108dp foreground/background layers (108-432 px) and
+    /// mipmap-anydpi-v26/<name>.xml are written too; the artwork is kept inside
+    /// the central 72dp safe zone of the foreground layer.
+    Android {
+        input: PathBuf,
+        res_dir: PathBuf,
+        /// Resource name for the generated files
+        #[clap(long, default_value = "ic_launcher")]
+        name: String,
+        /// Also emit adaptive icon foreground/background layers
+        #[clap(long)]
+        adaptive: bool,
+        /// Adaptive background layer color (RRGGBB[AA])
+        #[clap(long, value_parser = parse_hex_color, default_value = "FFFFFF")]
+        adaptive_background: Rgba<u8>,
+        /// Embed an ICC profile in PNG output: "srgb" for the built-in profile or a .icc path
+        #[clap(long, value_name = "srgb|PATH")]
+        icc_profile: Option<String>,
+    },
+    /// Write Linux desktop icons into the hicolor theme layout (hicolor/<size>x<size>/apps)
+    Linux {
+        input: PathBuf,
+        /// Icon theme root to create hicolor/ in, e.g. ~/.local/share/icons
+        icons_dir: PathBuf,
+        /// Icon name, usually the application ID matching Icon= in its .desktop file
+        #[clap(long)]
+        name: String,
+        /// Embed an ICC profile in PNG output: "srgb" for the built-in profile or a .icc path
+        #[clap(long, value_name = "srgb|PATH")]
+        icc_profile: Option<String>,
+    },
+    /// Build an icon from a short text label or single glyph rendered with a font
+    ///
+    /// The text is drawn on one line in --color, centered on a transparent
+    /// --size square and scaled so it fills 80% of it, then resized like `build`.
+    Text {
+        text: String,
+        #[clap(value_enum)]
+        format: TargetFormat,
+        output: PathBuf,
+        /// TrueType/OpenType font file used to render the text
+        #[clap(long)]
+        font: PathBuf,
+        /// Edge length in pixels of the square the text is rendered on
+        #[clap(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+        size: u32,
+        /// Text color (RRGGBB[AA])
+        #[clap(long, value_parser = parse_hex_color, default_value = "000000")]
+        color: Rgba<u8>,
+        #[command(flatten)]
+        sizes: SizeArgs,
+    },
+    /// Build an icon from image layers composited onto one square canvas
+    ///
+    /// Layers are given as PATH@X,Y (offset of the layer's top-left corner, may be
+    /// negative) and stacked in order, the first one at the bottom. Parts outside
+    /// the canvas are clipped. The result is resized like `build`.
+    Compose {
+        #[clap(value_enum)]
+        format: TargetFormat,
+        output: PathBuf,
+        /// Layers, bottom first: PATH@X,Y (a bare PATH is placed at 0,0)
+        #[clap(required = true, value_parser = parse_layer)]
+        layers: Vec<Layer>,
+        /// Edge length in pixels of the canvas
+        #[clap(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+        canvas: u32,
+        #[command(flatten)]
+        sizes: SizeArgs,
+    },
+    /// Report how much of an icon a launcher's circular mask could clip
+    ///
+    /// Measures the share of non-transparent pixels (weighted by alpha) that fall
+    /// outside the central safe circle, and fails when it exceeds --max-outside.
+    CheckMaskable {
+        input: PathBuf,
+        /// Safe zone to check against
+        #[clap(long, value_enum, default_value_t = SafeZone::Android)]
+        zone: SafeZone,
+        /// Treat INPUT as the source for `android --adaptive` and check the foreground it produces
+        #[clap(long)]
+        source: bool,
+        /// Largest acceptable share of the artwork outside the zone, in percent
+        #[clap(long, value_name = "PERCENT", default_value_t = 1.0, value_parser = parse_percent)]
+        max_outside: f64,
+        /// Write a preview PNG with the area outside the safe zone highlighted
+        #[clap(long, value_name = "PATH")]
+        overlay: Option<PathBuf>,
+    },
+    /// Rebuild a .cur from the <name>.meta.json and PNGs written by extracting one
+    BuildCursor { meta: PathBuf, output: PathBuf },
+    /// Report ICO entries with identical decoded pixels and optionally remove them
+    Optimize {
+        input: PathBuf,
+        /// Where to write the optimized icon (defaults to rewriting the input)
+        output: Option<PathBuf>,
+        /// Drop duplicate entries, keeping the first of each, and rewrite the directory
+        #[clap(long)]
+        dedupe: bool,
+        /// Also list each entry's number of distinct colors and whether it is opaque
+        #[clap(long)]
+        stats: bool,
+        /// List which entries were found to duplicate which
+        #[clap(long)]
+        debug: bool,
+    },
+    /// Combine several .ico files into one, one entry per size, without re-encoding
+    Merge {
+        #[clap(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+        #[clap(long, short)]
+        output: PathBuf,
+        /// Tie-break policy when inputs share a size, applied in order
+        #[clap(long, value_enum, value_delimiter = ',', default_values_t = [Prefer::HighestBpp, Prefer::Largest])]
+        prefer: Vec<Prefer>,
+        /// Print which entry each size would use without writing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Decode every ICO entry and store them all as PNG, as BMP or as the encoder chooses
+    Recompress {
+        input: PathBuf,
+        /// Where to write the recompressed icon (defaults to rewriting the input)
+        output: Option<PathBuf>,
+        #[clap(long, value_enum, default_value_t = EntryEncoding::Auto)]
+        encoding: EntryEncoding,
+    },
+    /// Convert an .icns to an .ico or vice versa, resizing for sizes the input lacks
+    Convert {
+        input: PathBuf,
+        /// Output icon; its extension (.ico or .icns) selects the format
+        output: PathBuf,
+    },
+    /// Replace the application icon of a Windows .exe/.dll with an .ico
+    ///
+    /// The first RT_GROUP_ICON resource is replaced (group #1 is added if there is
+    /// none) and the resource section is rebuilt, in place when it is the last
+    /// section of the file and otherwise as a new .rsrc section.
+    Embed {
+        exe: PathBuf,
+        ico: PathBuf,
+        /// Where to write the patched executable (defaults to rewriting the input)
+        output: Option<PathBuf>,
+    },
+    /// Pack <ostype>_<w>x<h>.png files (from `extract --all`) or an .iconset into an .icns without rescaling
+    Pack {
+        dir: PathBuf,
+        output: PathBuf,
+        /// Read an Apple .iconset folder (icon_16x16.png, icon_16x16@2x.png, ...) instead
+        #[clap(long)]
+        from_iconset: bool,
+        /// Print the element mapping and output path without writing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// List an .ico's entries (size, bit depth, encoding, bytes)
+    Info {
+        input: PathBuf,
+        /// Flag sizes stored only as PNG, which pre-Vista shells show blank, and suggest a fix
+        #[clap(long)]
+        compat_check: bool,
+    },
+    /// Check an .ico/.icns against a platform's required sizes and encodings
+    Validate {
+        input: PathBuf,
+        /// Platform whose requirements to check
+        #[clap(long, value_enum)]
+        spec: PlatformSpec,
+    },
+    /// Print a SHA-256 of an .ico/.icns's decoded images, independent of how they are encoded
+    Hash { input: PathBuf },
+    /// Render previews of an .ico/.icns
+    Preview {
+        input: PathBuf,
+        /// Write an animated PNG stepping through every entry, smallest to largest
+        #[clap(long, value_name = "OUT")]
+        animate: PathBuf,
+        /// How long each entry is shown in the animation, in milliseconds
+        #[clap(long, value_name = "MS", default_value_t = 500, value_parser = clap::value_parser!(u16).range(1..))]
+        frame_ms: u16,
     },
 }
 
@@ -416,53 +6076,1010 @@ enum Commands {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print nothing but errors (no debug output, warnings, plans or progress)
+    #[clap(long, global = true)]
+    quiet: bool,
+    /// Render at most N sizes in parallel (default: one per CPU; 1 = serial)
+    #[clap(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// zlib effort for PNG-encoded ICO entries and written PNG files
+    #[clap(long, global = true, value_enum, default_value_t = PngCompression::Default)]
+    png_compression: PngCompression,
 }
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
+    QUIET.store(cli.quiet, std::sync::atomic::Ordering::Relaxed);
+    THREADS.store(
+        cli.threads.unwrap_or(0) as usize,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    *PNG_COMPRESSION.lock().unwrap() = cli.png_compression;
+    // Taken before the arguments are moved out; `build --cache-dir` hashes it. Fields that only
+    // say where results go are blanked, so the same icon written elsewhere still hits.
+    let command_args = {
+        let mut command = cli.command.clone();
+        if let Commands::Build {
+            output,
+            report_json,
+            emit_rust,
+            cache_dir,
+            ..
+        } = &mut command
+        {
+            *output = PathBuf::new();
+            *report_json = None;
+            *emit_rust = None;
+            *cache_dir = None;
+        }
+        format!("{:?} {:?}", command, cli.png_compression)
+    };
     match cli.command {
         Commands::Extract {
             input,
             out_dir,
             debug,
+            dry_run,
+            prefer,
+            strict,
+            all,
+            keep_largest_only,
+            fit,
+            skip_unchanged,
+            max_dimension,
+            preserve_color_type,
+            output_template,
+            pre_multiply_check,
+            verbose_dib,
+            list_then_select,
+            index,
+            tolerant,
+            raw,
+            mask,
+            matte,
         } => {
-            let ext = input
+            let mut opts = ExtractOptions {
+                debug,
+                dry_run,
+                prefer,
+                strict,
+                all,
+                keep_largest_only,
+                fit: fit.unwrap_or(Fit::Contain),
+                skip_unchanged,
+                max_dimension,
+                preserve_color_type,
+                output_template,
+                pre_multiply_check,
+                verbose_dib,
+                list_entries: list_then_select,
+                index,
+                tolerant,
+                raw,
+                mask,
+                matte,
+                archive: None,
+            };
+            // A bare listing writes nothing, not even an empty archive.
+            let list_only = list_then_select && index.is_none();
+            if !dry_run
+                && !list_only
+                && out_dir
+                    .extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+            {
+                opts.archive = Some(ZipSink(RefCell::new(zip::ZipWriter::new(Cursor::new(
+                    Vec::new(),
+                )))));
+            }
+            let ext = input_extension(&input);
+            // An image extension makes OUT_DIR the path of the one extracted image itself.
+            let out_ext = out_dir
                 .extension()
-                .and_then(|s| s.to_str())
-                .unwrap_or("")
-                .to_ascii_lowercase();
+                .and_then(|e| e.to_str())
+                .map(str::to_ascii_lowercase);
+            let out_dir = match out_ext.as_deref() {
+                Some("png" | "bmp" | "jpg" | "jpeg" | "webp") if raw => bail!(
+                    "--raw names its files after each entry; give a directory, not {}",
+                    out_dir.display()
+                ),
+                Some("png" | "bmp" | "jpg" | "jpeg" | "webp") => {
+                    if all || keep_largest_only || ext == "ani" {
+                        bail!(
+                            "{} is a single file, but this extraction writes several images",
+                            out_dir.display()
+                        );
+                    }
+                    if opts.output_template.is_some() {
+                        bail!("--output-template can't be used when the output is a file path");
+                    }
+                    let name = out_dir.file_name().unwrap_or_default().to_string_lossy();
+                    opts.output_template =
+                        Some(OutputTemplate(vec![TemplatePart::Text(name.into_owned())]));
+                    match out_dir.parent() {
+                        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                        _ => PathBuf::from("."),
+                    }
+                }
+                _ => out_dir,
+            };
+            if ext == "icns" && (opts.list_entries || opts.index.is_some()) {
+                bail!(IconError::Unsupported(
+                    "--list-then-select/--index apply to ICO/CUR directories, not ICNS".into()
+                ));
+            }
+            if ext == "icns" && (tolerant || raw || mask) {
+                bail!(IconError::Unsupported(
+                    "--tolerant/--raw/--mask apply to ICO/CUR directories, not ICNS".into()
+                ));
+            }
             match ext.as_str() {
-                "ico" => extract_ico(&input, &out_dir, debug)?,
-                "icns" => extract_icns(&input, &out_dir, debug)?,
-                _ => bail!("Unsupported input extension: {}", ext),
+                "ico" | "cur" => extract_ico(&input, &out_dir, &opts)?,
+                "ani" => extract_ani(&input, &out_dir, &opts)?,
+                "exe" | "dll" => extract_pe(&input, &out_dir, &opts)?,
+                "icns" => extract_icns(&input, &out_dir, &opts)?,
+                _ => bail!(IconError::Unsupported(format!(
+                    "Unsupported input extension: {}",
+                    ext
+                ))),
+            }
+            if let Some(archive) = opts.archive.take() {
+                finish_archive(archive, &out_dir, &opts)?;
             }
         }
         Commands::Build {
             input,
             format,
             output,
+            config,
+            frame,
+            flip,
+            rotate,
             contain,
+            fit,
+            debug,
+            shadow,
+            background_image,
+            background_gradient,
+            letterbox,
+            flatten_against,
+            mask_from,
+            alpha_bleed,
+            square,
+            pre_square,
+            allow_nonsquare,
+            label,
+            label_font,
+            label_corner,
+            label_color,
+            filter,
+            auto_filter,
+            pixel_art,
+            size_rule,
+            size_src,
+            base_size,
+            warn_aspect,
+            strict,
+            strip_incomplete,
+            argb_small,
+            legacy_masks,
+            verify_after_write,
+            delete_invalid,
+            dual_256,
+            icc_profile,
+            alpha_threshold,
+            include_256_png_only,
+            sort_sizes,
+            monochrome,
+            mut sizes,
+            dry_run,
+            time,
+            report,
+            report_json,
+            emit_rust,
+            cache_dir,
+            max_file_size,
+            from_raw_entries,
         } => {
-            let img = load_image(&input)?;
-            match format {
-                TargetFormat::Ico => build_ico(&img, contain, &output)?,
-                TargetFormat::Icns => build_icns(&img, contain, &output)?,
+            let config_path = config.clone();
+            let config = config
+                .as_deref()
+                .map(load_build_config)
+                .transpose()?
+                .unwrap_or_default();
+            let Some(format) = format.or(config.format) else {
+                bail!("No output format: pass ico or icns, or set `format` in --config");
+            };
+            if from_raw_entries {
+                if !matches!(format, TargetFormat::Ico) {
+                    bail!(IconError::Unsupported(
+                        "--from-raw-entries packs ICO payloads; the format must be ico".into()
+                    ));
+                }
+                return pack_raw_entries(&input, &output, dry_run);
+            }
+            let cache = match cache_dir.as_deref().filter(|_| !dry_run) {
+                Some(dir) => {
+                    let extension = match format {
+                        TargetFormat::Ico => "ico",
+                        TargetFormat::Icns => "icns",
+                    };
+                    let icc_file = icc_profile
+                        .as_deref()
+                        .map(Path::new)
+                        .filter(|p| p.is_file());
+                    let files: Vec<&Path> = [Some(input.as_path()), config_path.as_deref()]
+                        .into_iter()
+                        .chain([
+                            background_image.as_deref(),
+                            mask_from.as_deref(),
+                            label_font.as_deref(),
+                            icc_file,
+                        ])
+                        .flatten()
+                        .chain(size_src.iter().map(|src| src.path.as_path()))
+                        .collect();
+                    let key = build_cache_key(&command_args, &files)?;
+                    let cached = dir.join(format!("{}.{}", key, extension));
+                    if cached.is_file() {
+                        if let Some(parent) = output.parent() {
+                            ensure_dir(parent)?;
+                        }
+                        fs::copy(&cached, &output)
+                            .with_context(|| format!("write {}", output.display()))?;
+                        if let Some(rs) = &emit_rust {
+                            write_rust_include(&output, rs)?;
+                        }
+                        status!(
+                            "{} unchanged; reused {}",
+                            output.display(),
+                            cached.display()
+                        );
+                        return Ok(());
+                    }
+                    Some((dir, key, extension))
+                }
+                None => None,
+            };
+            if allow_nonsquare && matches!(format, TargetFormat::Icns) {
+                bail!(IconError::Unsupported(
+                    "--allow-nonsquare with ICNS output; every element type is square".into()
+                ));
+            }
+            let fit = fit
+                .or(contain.map(|c| if c { Fit::Contain } else { Fit::Cover }))
+                .or(allow_nonsquare.then_some(Fit::Contain))
+                .or(config.fit)
+                .unwrap_or(Fit::Contain);
+            let filter = filter.or(config.filter);
+            let letterbox = letterbox.or(config.background);
+            if sizes.only_size.is_none() {
+                sizes.sizes = sizes.sizes.or(config.sizes);
+            }
+            let sizes = sizes.resolve(format)?;
+            for rule in size_rule.iter().filter(|r| !sizes.contains(&r.size)) {
+                diag!(
+                    "Warning: --size-rule for {}px matches no size being built",
+                    rule.size
+                );
+            }
+            for src in size_src.iter().filter(|src| !sizes.contains(&src.size)) {
+                diag!(
+                    "Warning: --size-src for {}px matches no size being built",
+                    src.size
+                );
+            }
+            let started = Instant::now();
+            if time {
+                *TIMINGS.lock().unwrap() = Some(Vec::new());
+            }
+            if report || report_json.is_some() {
+                *REPORT.lock().unwrap() = Some(Vec::new());
+            }
+            let mut img = timed(|| "load".into(), || load_image_frame(&input, frame))?;
+            let (source_w, source_h) = img.dimensions();
+            img = transform_source(img, flip, rotate);
+            let contain = match fit {
+                Fit::Contain => true,
+                Fit::Cover => false,
+                Fit::Both => bail!(
+                    "--fit both only applies to PNG sets (extract --keep-largest-only); an icon holds one image per size"
+                ),
+                Fit::Auto => {
+                    let share = transparent_share(&img);
+                    let contain = share >= FIT_AUTO_TRANSPARENT_MIN;
+                    if debug {
+                        diag!(
+                            "[debug] --fit auto: {:.1}% of pixels are not fully opaque (threshold {}%), using {}",
+                            share * 100.0,
+                            FIT_AUTO_TRANSPARENT_MIN * 100.0,
+                            if contain { "contain" } else { "cover" }
+                        );
+                    }
+                    contain
+                }
+            };
+            if let (Some(ratio), None, None) = (warn_aspect, square, pre_square) {
+                check_aspect(&img, ratio, contain, strict)?;
+            }
+            // Shared with the --size-src images, so they are framed like the main source.
+            let prepare = |mut img: DynamicImage| {
+                if let Some(anchor) = square {
+                    img = crop_to_square(&img, anchor);
+                }
+                if let Some(color) = pre_square {
+                    img = pad_to_square(&img, color);
+                }
+                if let Some(color) = flatten_against {
+                    img = DynamicImage::ImageRgba8(flatten(&img.to_rgba8(), color));
+                }
+                if alpha_bleed {
+                    // Also bleed the source, so resizing doesn't mix hidden black into the edges.
+                    let mut rgba = img.to_rgba8();
+                    bleed_edge_colors(&mut rgba);
+                    img = DynamicImage::ImageRgba8(rgba);
+                }
+                img
+            };
+            img = prepare(img);
+            if let Some(base) = base_size {
+                if let Some(&largest) = sizes.last().filter(|&&s| s > base) {
+                    diag!(
+                        "Warning: --base-size {} is below the largest target size {}; larger sizes will be upscaled",
+                        base,
+                        largest
+                    );
+                }
+                img = downscale_to_base(img, base);
             }
+            if dry_run {
+                let plan: Vec<(u32, &Path)> = sizes
+                    .iter()
+                    .map(|&s| {
+                        let src = size_src.iter().rev().find(|src| src.size == s);
+                        (s, src.map_or(input.as_path(), |src| src.path.as_path()))
+                    })
+                    .collect();
+                print_build_plan(&plan, &output);
+                if let Some(rs) = &emit_rust {
+                    status!("would write {}", rs.display());
+                }
+                return Ok(());
+            }
+            let background_image = background_image
+                .as_deref()
+                .map(|p| timed(|| "load background".into(), || load_image(p)))
+                .transpose()?;
+            let mask = mask_from
+                .as_deref()
+                .map(|p| timed(|| "load mask".into(), || load_image(p)))
+                .transpose()?;
+            let size_sources = size_src
+                .iter()
+                .map(|src| {
+                    let img = timed(
+                        || format!("load {}px source", src.size),
+                        || load_image(&src.path),
+                    )?;
+                    Ok((src.size, prepare(transform_source(img, flip, rotate))))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // The flattened source is opaque, so only contain-fit padding could stay transparent.
+            let letterbox = flatten_against
+                .map(|c| Rgba([c[0], c[1], c[2], 0xFF]))
+                .or(letterbox);
+            let label = match (label, label_font) {
+                (Some(text), Some(font)) => {
+                    let font_data =
+                        fs::read(&font).with_context(|| format!("read font {}", font.display()))?;
+                    // Fail on an unreadable font here rather than drawing blank ribbons.
+                    render_text(font_data.clone(), &text, 64, label_color)?;
+                    Some(Label {
+                        text,
+                        font: font_data,
+                        corner: label_corner,
+                        color: label_color,
+                    })
+                }
+                _ => None,
+            };
+            let opts = RenderOptions {
+                contain,
+                shadow,
+                background_image,
+                background_gradient,
+                letterbox,
+                mask,
+                alpha_bleed,
+                filter,
+                auto_filter,
+                size_rules: size_rule,
+                size_sources,
+                pixel_art,
+                nonsquare: allow_nonsquare,
+                label,
+            };
+            let ico_opts = IcoOptions {
+                dual_256,
+                icc_profile: icc_profile.as_deref().map(load_icc_profile).transpose()?,
+                alpha_threshold,
+                png_min_size: include_256_png_only,
+                sort_sizes,
+                monochrome,
+                max_file_size: max_file_size.map(|kb| kb * 1024),
+            };
+            let written = match format {
+                TargetFormat::Ico => {
+                    build_ico(&img, &sizes, &opts, &ico_opts, &output)?;
+                    sizes
+                }
+                TargetFormat::Icns => {
+                    let icns_opts = IcnsOptions {
+                        strip_incomplete,
+                        argb_small,
+                        legacy_masks,
+                        max_file_size: max_file_size.map(|kb| kb * 1024),
+                    };
+                    build_icns(&img, &sizes, &opts, &icns_opts, &output)?
+                }
+            };
+            if verify_after_write {
+                timed(
+                    || "verify".into(),
+                    || verify_output(&output, format, &written, delete_invalid),
+                )?;
+            }
+            if let Some((dir, key, extension)) = &cache {
+                store_in_cache(dir, key, extension, &output, &input)?;
+            }
+            if let Some(rs) = &emit_rust {
+                write_rust_include(&output, rs)?;
+            }
+            print_report(
+                (&input, source_w, source_h),
+                &output,
+                &img,
+                &opts,
+                report,
+                report_json.as_deref(),
+            )?;
+            print_timings(started.elapsed());
         }
         Commands::BuildDir {
             dir,
             format,
             output,
+            sizes,
+            exact_size_required,
+            dry_run,
+        } => {
+            let sizes = sizes.resolve(format)?;
+            build_from_dir(&dir, format, &sizes, &output, exact_size_required, dry_run)?;
+        }
+        Commands::AppleTouch {
+            input,
+            out_dir,
+            background,
+            icc_profile,
+        } => {
+            let img = load_image(&input)?;
+            let icc_profile = icc_profile.as_deref().map(load_icc_profile).transpose()?;
+            build_apple_touch(&img, background, icc_profile.as_deref(), &out_dir)?;
+        }
+        Commands::Favicon {
+            input,
+            out_dir,
+            manifest_json,
+            src_prefix,
+            icc_profile,
+        } => {
+            let img = load_image(&input)?;
+            let icc_profile = icc_profile.as_deref().map(load_icc_profile).transpose()?;
+            build_favicon(
+                &img,
+                icc_profile.as_deref(),
+                &out_dir,
+                manifest_json.as_deref(),
+                &src_prefix,
+            )?;
+        }
+        Commands::Android {
+            input,
+            res_dir,
+            name,
+            adaptive,
+            adaptive_background,
+            icc_profile,
+        } => {
+            let img = load_image(&input)?;
+            let adaptive = adaptive.then_some(AndroidAdaptive {
+                background: adaptive_background,
+            });
+            let icc_profile = icc_profile.as_deref().map(load_icc_profile).transpose()?;
+            build_android(
+                &img,
+                &name,
+                adaptive.as_ref(),
+                icc_profile.as_deref(),
+                &res_dir,
+            )?;
+        }
+        Commands::Linux {
+            input,
+            icons_dir,
+            name,
+            icc_profile,
+        } => {
+            let img = load_image(&input)?;
+            let icc_profile = icc_profile.as_deref().map(load_icc_profile).transpose()?;
+            build_linux(&img, &name, icc_profile.as_deref(), &icons_dir)?;
+        }
+        Commands::Text {
+            text,
+            format,
+            output,
+            font,
+            size,
+            color,
+            sizes,
+        } => {
+            let sizes = sizes.resolve(format)?;
+            let font_data =
+                fs::read(&font).with_context(|| format!("read font {}", font.display()))?;
+            let img = DynamicImage::ImageRgba8(render_text(font_data, &text, size, color)?);
+            let opts = RenderOptions::contain();
+            match format {
+                TargetFormat::Ico => {
+                    build_ico(&img, &sizes, &opts, &IcoOptions::default(), &output)?
+                }
+                TargetFormat::Icns => {
+                    build_icns(&img, &sizes, &opts, &IcnsOptions::default(), &output)?;
+                }
+            }
+        }
+        Commands::Compose {
+            format,
+            output,
+            layers,
+            canvas,
+            sizes,
+        } => {
+            let sizes = sizes.resolve(format)?;
+            let img = DynamicImage::ImageRgba8(compose_layers(&layers, canvas)?);
+            let opts = RenderOptions::contain();
+            match format {
+                TargetFormat::Ico => {
+                    build_ico(&img, &sizes, &opts, &IcoOptions::default(), &output)?
+                }
+                TargetFormat::Icns => {
+                    build_icns(&img, &sizes, &opts, &IcnsOptions::default(), &output)?;
+                }
+            }
+        }
+        Commands::CheckMaskable {
+            input,
+            zone,
+            source,
+            max_outside,
+            overlay,
+        } => {
+            check_maskable(
+                &input,
+                zone,
+                source,
+                max_outside / 100.0,
+                overlay.as_deref(),
+            )?;
+        }
+        Commands::BuildCursor { meta, output } => {
+            build_cursor(&meta, &output)?;
+        }
+        Commands::Optimize {
+            input,
+            output,
+            dedupe,
+            stats,
+            debug,
+        } => {
+            let output = output.unwrap_or_else(|| input.clone());
+            optimize_ico(&input, &output, dedupe, stats, debug)?;
+        }
+        Commands::Merge {
+            inputs,
+            output,
+            prefer,
+            dry_run,
+        } => {
+            merge_icos(&inputs, &output, &prefer, dry_run)?;
+        }
+        Commands::Recompress {
+            input,
+            output,
+            encoding,
+        } => {
+            let output = output.unwrap_or_else(|| input.clone());
+            recompress_ico(&input, &output, encoding)?;
+        }
+        Commands::Convert { input, output } => {
+            convert_icon(&input, &output)?;
+        }
+        Commands::Embed { exe, ico, output } => {
+            let output = output.unwrap_or_else(|| exe.clone());
+            embed_ico(&exe, &ico, &output)?;
+        }
+        Commands::Pack {
+            dir,
+            output,
+            from_iconset,
+            dry_run,
+        } => {
+            pack_icns(&dir, &output, from_iconset, dry_run)?;
+        }
+        Commands::Info {
+            input,
+            compat_check,
+        } => {
+            ico_info(&input, compat_check)?;
+        }
+        Commands::Validate { input, spec } => {
+            validate_icon(&input, spec)?;
+        }
+        Commands::Hash { input } => {
+            status!("{}  {}", content_hash(&input)?, input.display());
+        }
+        Commands::Preview {
+            input,
+            animate,
+            frame_ms,
         } => {
-            build_from_dir(&dir, format, &output)?;
+            preview_animation(&input, &animate, frame_ms)?;
         }
     }
     Ok(())
 }
 
+// Exit codes: 1 generic, 2 unsupported format, 3 malformed input, 4 I/O error.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    use std::io::ErrorKind;
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<IconError>() {
+            return match e {
+                IconError::Unsupported(_) => 2,
+                IconError::Malformed(_) => 3,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<image::ImageError>() {
+            return match e {
+                image::ImageError::Unsupported(_) => 2,
+                image::ImageError::Decoding(_) => 3,
+                image::ImageError::IoError(_) => 4,
+                _ => 1,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                ErrorKind::UnexpectedEof | ErrorKind::InvalidData => 3,
+                _ => 4,
+            };
+        }
+    }
+    1
+}
+
 fn main() {
     if let Err(e) = run() {
         eprintln!("Error: {:#}", e);
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A per-process scratch path, so parallel test runs don't collide.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("icon-rust-test-{}-{}", std::process::id(), name))
+    }
+
+    // Extreme aspect ratios must still fill exactly size x size, without crop-math panics.
+    #[test]
+    fn degenerate_sources_render_square() {
+        let sources = [(1, 1), (10000, 1), (1, 10000)];
+        for (w, h) in sources {
+            let img =
+                DynamicImage::ImageRgba8(RgbaImage::from_pixel(w, h, Rgba([200, 40, 40, 255])));
+            for contain in [true, false] {
+                let opts = RenderOptions {
+                    contain,
+                    ..RenderOptions::contain()
+                };
+                for size in [1, 16, 17, 256] {
+                    let out = resized_rgba(&img, size, &opts);
+                    assert_eq!(
+                        out.dimensions(),
+                        (size, size),
+                        "{}x{} source, contain={}",
+                        w,
+                        h,
+                        contain
+                    );
+                }
+            }
+        }
+    }
+
+    // Sizes off the usual power-of-two ladder come back at exactly their own dimensions.
+    #[test]
+    fn odd_sizes_round_trip_through_ico() {
+        // Mirror-symmetric left to right, so a centered cover crop stays symmetric.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 40, |x, y| {
+            Rgba([x.min(63 - x) as u8 * 8, y as u8 * 6, 128, 255])
+        }));
+        let sizes = [17, 20, 29, 33, 44, 100];
+        for contain in [true, false] {
+            let opts = RenderOptions {
+                contain,
+                ..RenderOptions::contain()
+            };
+            let out = temp_path("odd-sizes.ico");
+            build_ico(&img, &sizes, &opts, &IcoOptions::default(), &out).unwrap();
+            let entries = read_ico_entries(&out).unwrap();
+            fs::remove_file(&out).unwrap();
+            assert_eq!(entries.len(), sizes.len());
+            for (entry, &size) in entries.iter().zip(&sizes) {
+                assert_eq!((entry.width, entry.height), (size, size));
+                let rgba = decode_ico_blob(&entry.data, false, false).unwrap();
+                assert_eq!(rgba, resized_rgba(&img, size, &opts), "{}px", size);
+                for (x, y, p) in rgba.enumerate_pixels() {
+                    let q = rgba.get_pixel(size - 1 - x, y);
+                    let off = p.0.iter().zip(q.0).any(|(&a, b)| a.abs_diff(b) > 1);
+                    assert!(
+                        !off,
+                        "{}px contain={} asymmetric at {},{}",
+                        size, contain, x, y
+                    );
+                }
+            }
+        }
+    }
+
+    // An ICO DIB entry: header (masks inside V2+ headers, after a 40-byte one), bottom-up
+    // rows as given and an all-clear AND mask.
+    fn dib_blob(
+        header_size: usize,
+        w: u32,
+        h: u32,
+        bpp: u16,
+        masks: &[u32],
+        rows: &[u8],
+    ) -> Vec<u8> {
+        let mut blob = vec![0u8; header_size];
+        blob[0..4].copy_from_slice(&(header_size as u32).to_le_bytes());
+        blob[4..8].copy_from_slice(&w.to_le_bytes());
+        blob[8..12].copy_from_slice(&(h * 2).to_le_bytes());
+        blob[12..14].copy_from_slice(&1u16.to_le_bytes());
+        blob[14..16].copy_from_slice(&bpp.to_le_bytes());
+        let compression = if masks.is_empty() {
+            BI_RGB
+        } else {
+            BI_BITFIELDS
+        };
+        blob[16..20].copy_from_slice(&compression.to_le_bytes());
+        let mask_bytes: Vec<u8> = masks.iter().flat_map(|m| m.to_le_bytes()).collect();
+        if header_size >= 52 {
+            blob[40..40 + mask_bytes.len()].copy_from_slice(&mask_bytes);
+        } else {
+            blob.extend(&mask_bytes);
+        }
+        blob.extend(rows);
+        blob.extend(vec![0u8; w.div_ceil(32) as usize * 4 * h as usize]);
+        blob
+    }
+
+    #[test]
+    fn bitfields_565_decodes() {
+        // Red, green; two 16-bit pixels fill the 4-byte row.
+        let rows = [0x00, 0xF8, 0xE0, 0x07];
+        let blob = dib_blob(40, 2, 1, 16, &[0xF800, 0x07E0, 0x001F], &rows);
+        let rgba = decode_ico_blob(&blob, false, false).unwrap();
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(rgba.get_pixel(1, 0), &Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn bitfields_555_decodes() {
+        // Blue, then mid gray (16 of 31 per channel).
+        let gray: u16 = (16 << 10) | (16 << 5) | 16;
+        let mut rows = vec![0x1F, 0x00];
+        rows.extend(gray.to_le_bytes());
+        let blob = dib_blob(40, 2, 1, 16, &[0x7C00, 0x03E0, 0x001F], &rows);
+        let rgba = decode_ico_blob(&blob, false, false).unwrap();
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+        assert_eq!(rgba.get_pixel(1, 0), &Rgba([132, 132, 132, 255]));
+    }
+
+    #[test]
+    fn v5_header_with_explicit_masks_decodes() {
+        // 124-byte BITMAPV5HEADER, 32bpp with R, G, B, A in byte order instead of BGRA.
+        let masks = [0x0000_00FF, 0x0000_FF00, 0x00FF_0000, 0xFF00_0000];
+        let blob = dib_blob(124, 1, 1, 32, &masks, &[10, 20, 30, 40]);
+        let rgba = decode_ico_blob(&blob, false, false).unwrap();
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([10, 20, 30, 40]));
+    }
+
+    #[test]
+    fn truncated_argb_element_names_its_type() {
+        // One ic04 element whose first RLE run promises six literal bytes but holds one.
+        let payload = [&ARGB_MAGIC[..], &[0x05, 0xFF]].concat();
+        let mut file = b"icns".to_vec();
+        file.extend((16 + payload.len() as u32).to_be_bytes());
+        file.extend(b"ic04");
+        file.extend((8 + payload.len() as u32).to_be_bytes());
+        file.extend(&payload);
+        let mut r = Cursor::new(file);
+        let entries = index_icns(&mut r).unwrap();
+        let err = decode_icns_entry(&mut r, &entries, 0).unwrap_err();
+        match err.downcast_ref::<IconError>() {
+            Some(IconError::Malformed(msg)) => assert!(msg.contains("ic04"), "{}", msg),
+            _ => panic!("expected a Malformed error, got {:#}", err),
+        }
+    }
+
+    fn bmp_entry(size: u32, bitcount: u16) -> IcoEntry {
+        IcoEntry {
+            width: size,
+            height: size,
+            bitcount,
+            colors: None,
+            data: Vec::new(),
+            hotspot: None,
+        }
+    }
+
+    #[test]
+    fn ico_entries_sort_by_size_then_bit_depth() {
+        let mut entries = vec![
+            bmp_entry(32, 32),
+            bmp_entry(16, 8),
+            bmp_entry(48, 32),
+            bmp_entry(16, 32),
+            bmp_entry(32, 4),
+            bmp_entry(16, 4),
+        ];
+        normalize_ico_order(&mut entries, SortOrder::Asc);
+        let order: Vec<(u32, u16)> = entries.iter().map(|e| (e.width, e.bitcount)).collect();
+        assert_eq!(
+            order,
+            [(16, 4), (16, 8), (16, 32), (32, 4), (32, 32), (48, 32)]
+        );
+    }
+
+    #[test]
+    fn sort_sizes_desc_writes_largest_first() {
+        let sizes = [16, 48, 32];
+        let rendered = sizes
+            .iter()
+            .map(|&s| RgbaImage::from_pixel(s, s, Rgba([0, 0, 0, 128])))
+            .collect();
+        let ico_opts = IcoOptions {
+            sort_sizes: SortOrder::Desc,
+            ..IcoOptions::default()
+        };
+        let out = temp_path("desc.ico");
+        write_ico(&sizes, rendered, &ico_opts, &out).unwrap();
+        let bytes = fs::read(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        let count = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+        let widths: Vec<u8> = (0..count).map(|i| bytes[6 + 16 * i]).collect();
+        assert_eq!(widths, [48, 32, 16]);
+    }
+
+    #[test]
+    fn argb_small_elements_round_trip() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([x as u8 * 4, y as u8 * 4, 90, (x + y) as u8 * 2])
+        }));
+        let opts = RenderOptions::contain();
+        let icns_opts = IcnsOptions {
+            argb_small: true,
+            ..IcnsOptions::default()
+        };
+        let out = temp_path("argb.icns");
+        build_icns(&img, &[16, 32], &opts, &icns_opts, &out).unwrap();
+        let mut r = Cursor::new(fs::read(&out).unwrap());
+        fs::remove_file(&out).unwrap();
+        let entries = index_icns(&mut r).unwrap();
+        let names: Vec<String> = entries.iter().map(|e| ostype_name(e.ostype)).collect();
+        assert_eq!(names, ["ic04", "ic05"]);
+        for (index, size) in [(0, 16), (1, 32)] {
+            let (decoded, _) = decode_icns_entry(&mut r, &entries, index).unwrap();
+            assert_eq!(decoded, resized_rgba(&img, size, &opts), "{}px", size);
+        }
+    }
+
+    #[test]
+    fn letterbox_fills_padding_and_keeps_artwork_alpha() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, Rgba([200, 40, 40, 128])));
+        let color = Rgba([0, 0, 255, 255]);
+        let opts = RenderOptions {
+            letterbox: Some(color),
+            filter: Some(ResizeFilter::Nearest),
+            ..RenderOptions::contain()
+        };
+        let out = resized_rgba(&img, 4, &opts);
+        for (x, y, p) in out.enumerate_pixels() {
+            if y == 0 || y == 3 {
+                assert_eq!(*p, color, "padding at {},{}", x, y);
+            } else {
+                assert_eq!(*p, Rgba([200, 40, 40, 128]), "artwork at {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn letterbox_lies_beneath_the_shadow() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 4, Rgba([200, 40, 40, 255])));
+        let color = Rgba([255, 255, 255, 255]);
+        let opts = RenderOptions {
+            letterbox: Some(color),
+            shadow: Some(Shadow {
+                dx: 0.0,
+                dy: 64.0,
+                blur: 0.0,
+                color: Rgba([0, 0, 0, 255]),
+            }),
+            filter: Some(ResizeFilter::Nearest),
+            ..RenderOptions::contain()
+        };
+        let out = resized_rgba(&img, 8, &opts);
+        for (x, y, p) in out.enumerate_pixels() {
+            let expected = match y {
+                0 | 1 => color,
+                2..=5 => Rgba([200, 40, 40, 255]),
+                _ => Rgba([0, 0, 0, 255]),
+            };
+            assert_eq!(*p, expected, "pixel {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn build_cursor_keeps_monochrome_entries_1bpp() {
+        let dir = temp_path("mono-cursor");
+        fs::create_dir_all(&dir).unwrap();
+        let img = RgbaImage::from_fn(32, 32, |x, _| match x % 3 {
+            0 => Rgba([0, 0, 0, 255]),
+            1 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        fs::write(dir.join("0_32x32.png"), encode_png(&img, None).unwrap()).unwrap();
+        let meta = r#"{"type": "cursor", "entries": [{"file": "0_32x32.png", "width": 32,
+            "height": 32, "bitcount": 1, "png": false, "hotspot": {"x": 3, "y": 5}}]}"#;
+        fs::write(dir.join("arrow.meta.json"), meta).unwrap();
+        let out = dir.join("arrow.cur");
+        build_cursor(&dir.join("arrow.meta.json"), &out).unwrap();
+        let bytes = fs::read(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let d = &bytes[6..22];
+        assert_eq!(u16::from_le_bytes([d[4], d[5]]), 3);
+        assert_eq!(u16::from_le_bytes([d[6], d[7]]), 5);
+        let offset = u32::from_le_bytes([d[12], d[13], d[14], d[15]]) as usize;
+        let blob = &bytes[offset..];
+        assert_eq!(u16::from_le_bytes([blob[14], blob[15]]), 1);
+        assert_eq!(decode_ico_blob(blob, false, false).unwrap(), img);
+    }
+
+    #[test]
+    fn palette_png_is_byte_identical_across_runs() {
+        let img = RgbaImage::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, ((x ^ y) * 8) as u8, 255])
+        });
+        let first = encode_png_reduced(&img, None).unwrap();
+        assert_eq!(first, encode_png_reduced(&img, None).unwrap());
+        let reader = png::Decoder::new(Cursor::new(&first)).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        // Palette entries follow first appearance in row-major order.
+        assert_eq!(&info.palette.as_deref().unwrap()[..6], [0, 0, 0, 16, 0, 8]);
     }
 }
@@ -0,0 +1,618 @@
+// Minimal reader/writer for Windows PE (.exe/.dll) resource sections: enough to pull
+// icon groups out as standalone .ico files and to put a new application icon in.
+
+use std::borrow::Cow;
+
+use anyhow::{Result, bail};
+
+use crate::IconError;
+
+pub const RT_ICON: u32 = 3;
+pub const RT_GROUP_ICON: u32 = 14;
+
+const LANG_NEUTRAL: u32 = 0;
+// IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+const RSRC_CHARACTERISTICS: u32 = 0x4000_0040;
+
+/// Resource type or name: numeric ID or UTF-16 string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResId {
+    Id(u32),
+    Name(String),
+}
+
+impl std::fmt::Display for ResId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResId::Id(id) => write!(f, "#{}", id),
+            ResId::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl ResId {
+    // Directory order the loader's binary search expects: names (case-insensitive)
+    // before IDs, each ascending.
+    fn sort_key(&self) -> (u8, String, u32) {
+        match self {
+            ResId::Name(name) => (0, name.to_uppercase(), 0),
+            ResId::Id(id) => (1, String::new(), *id),
+        }
+    }
+}
+
+/// One leaf of the resource tree; language variants appear as separate leaves.
+#[derive(Clone, Debug)]
+pub struct Resource<'a> {
+    pub kind: ResId,
+    pub name: ResId,
+    pub lang: u32,
+    pub data: Cow<'a, [u8]>,
+}
+
+fn u16_at(data: &[u8], off: usize) -> Result<u16> {
+    match data.get(off..off + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => bail!(IconError::Malformed("Truncated PE file".into())),
+    }
+}
+
+fn u32_at(data: &[u8], off: usize) -> Result<u32> {
+    match data.get(off..off + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => bail!(IconError::Malformed("Truncated PE file".into())),
+    }
+}
+
+fn put_u16(data: &mut [u8], off: usize, v: u16) {
+    data[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], off: usize, v: u32) {
+    data[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn align(v: u32, to: u32) -> u32 {
+    v.div_ceil(to.max(1)) * to.max(1)
+}
+
+/// Section table entry.
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+    /// File offset of this entry in the section table.
+    pub header_offset: usize,
+}
+
+/// Parsed PE headers, plus the file offsets `embed_icon` patches.
+#[derive(Clone, Debug)]
+pub struct PeHeaders {
+    pub sections: Vec<Section>,
+    pub resource_rva: u32,
+    coff: usize,
+    optional: usize,
+    data_dirs: usize,
+    data_dir_count: u32,
+    section_table_end: usize,
+}
+
+pub fn parse_headers(data: &[u8]) -> Result<PeHeaders> {
+    if data.get(0..2) != Some(b"MZ") {
+        bail!(IconError::Unsupported(
+            "Not a PE file (missing MZ header)".into()
+        ));
+    }
+    let pe = u32_at(data, 0x3C)? as usize;
+    if data.get(pe..pe + 4) != Some(b"PE\0\0") {
+        bail!(IconError::Unsupported(
+            "Not a PE file (missing PE signature)".into()
+        ));
+    }
+    let coff = pe + 4;
+    let section_count = u16_at(data, coff + 2)? as usize;
+    let optional_size = u16_at(data, coff + 16)? as usize;
+    let optional = coff + 20;
+    // Data directories follow the fixed part of the optional header, which is 16 bytes
+    // longer in PE32+ (64-bit) images.
+    let data_dirs = match u16_at(data, optional)? {
+        0x10B => optional + 96,
+        0x20B => optional + 112,
+        magic => bail!(IconError::Unsupported(format!(
+            "Unknown PE optional header magic {:#x}",
+            magic
+        ))),
+    };
+    // The resource table is data directory #2.
+    let data_dir_count = u32_at(data, data_dirs - 4)?;
+    let resource_rva = if data_dir_count > 2 {
+        u32_at(data, data_dirs + 2 * 8)?
+    } else {
+        0
+    };
+    let table = optional + optional_size;
+    let mut sections = Vec::with_capacity(section_count);
+    for i in 0..section_count {
+        let s = table + i * 40;
+        sections.push(Section {
+            virtual_size: u32_at(data, s + 8)?,
+            virtual_address: u32_at(data, s + 12)?,
+            raw_size: u32_at(data, s + 16)?,
+            raw_offset: u32_at(data, s + 20)?,
+            header_offset: s,
+        });
+    }
+    Ok(PeHeaders {
+        sections,
+        resource_rva,
+        coff,
+        optional,
+        data_dirs,
+        data_dir_count,
+        section_table_end: table + section_count * 40,
+    })
+}
+
+impl PeHeaders {
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        self.sections.iter().find_map(|s| {
+            let span = s.virtual_size.max(s.raw_size);
+            (rva >= s.virtual_address && rva - s.virtual_address < span)
+                .then(|| (rva - s.virtual_address + s.raw_offset) as usize)
+        })
+    }
+}
+
+// A resource directory entry points at either another directory or a data entry.
+enum Node {
+    Dir(usize),
+    Leaf(usize),
+}
+
+// Entries of the IMAGE_RESOURCE_DIRECTORY at `off`; named entries come first, then IDs.
+fn directory(rsrc: &[u8], off: usize) -> Result<Vec<(ResId, Node)>> {
+    let count = u16_at(rsrc, off + 12)? as usize + u16_at(rsrc, off + 14)? as usize;
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let e = off + 16 + i * 8;
+        let name = u32_at(rsrc, e)?;
+        let target = u32_at(rsrc, e + 4)?;
+        let id = if name & 0x8000_0000 != 0 {
+            // IMAGE_RESOURCE_DIR_STRING_U: length-prefixed UTF-16.
+            let s = (name & 0x7FFF_FFFF) as usize;
+            let len = u16_at(rsrc, s)? as usize;
+            let units = (0..len)
+                .map(|k| u16_at(rsrc, s + 2 + k * 2))
+                .collect::<Result<Vec<_>>>()?;
+            ResId::Name(String::from_utf16_lossy(&units))
+        } else {
+            ResId::Id(name)
+        };
+        let node = if target & 0x8000_0000 != 0 {
+            Node::Dir((target & 0x7FFF_FFFF) as usize)
+        } else {
+            Node::Leaf(target as usize)
+        };
+        entries.push((id, node));
+    }
+    Ok(entries)
+}
+
+/// Every resource in the file, in directory order. Empty when there is no resource section.
+pub fn resources(data: &[u8]) -> Result<Vec<Resource<'_>>> {
+    let headers = parse_headers(data)?;
+    if headers.resource_rva == 0 {
+        return Ok(Vec::new());
+    }
+    // Offsets inside the tree are relative to the start of the resource section.
+    let Some(rsrc) = headers
+        .rva_to_offset(headers.resource_rva)
+        .and_then(|root| data.get(root..))
+    else {
+        bail!(IconError::Malformed(
+            "Resource directory outside the file".into()
+        ));
+    };
+    let mut out = Vec::new();
+    for (kind, types) in directory(rsrc, 0)? {
+        let Node::Dir(types) = types else { continue };
+        for (name, langs) in directory(rsrc, types)? {
+            let Node::Dir(langs) = langs else { continue };
+            for (lang, leaf) in directory(rsrc, langs)? {
+                let (ResId::Id(lang), Node::Leaf(entry)) = (lang, leaf) else {
+                    continue;
+                };
+                let rva = u32_at(rsrc, entry)?;
+                let size = u32_at(rsrc, entry + 4)? as usize;
+                let Some(bytes) = headers
+                    .rva_to_offset(rva)
+                    .and_then(|o| data.get(o..o.checked_add(size)?))
+                else {
+                    bail!(IconError::Malformed(format!(
+                        "Resource {}/{} points outside the file",
+                        kind, name
+                    )));
+                };
+                out.push(Resource {
+                    kind: kind.clone(),
+                    name: name.clone(),
+                    lang,
+                    data: Cow::Borrowed(bytes),
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// An RT_GROUP_ICON resource reassembled into a standalone .ico file.
+#[derive(Clone, Debug)]
+pub struct IconGroup {
+    pub name: ResId,
+    pub ico: Vec<u8>,
+}
+
+// Each GRPICONDIRENTRY mirrors an ICO directory entry, except that the trailing
+// 4-byte file offset is replaced by the 2-byte ID of the RT_ICON holding the image.
+// Returns the 12 shared directory bytes and the RT_ICON ID of every member.
+fn group_members(dir: &[u8], name: &ResId) -> Result<Vec<([u8; 12], u32)>> {
+    let count = u16_at(dir, 4)? as usize;
+    let mut members = Vec::with_capacity(count);
+    for i in 0..count {
+        let e = 6 + i * 14;
+        let Some(fields) = dir.get(e..e + 12) else {
+            bail!(IconError::Malformed(format!(
+                "Truncated icon group {}",
+                name
+            )));
+        };
+        members.push((fields.try_into().unwrap(), u16_at(dir, e + 12)? as u32));
+    }
+    Ok(members)
+}
+
+pub fn icon_groups(data: &[u8]) -> Result<Vec<IconGroup>> {
+    let all = resources(data)?;
+    let icon = |id: u32| {
+        all.iter()
+            .find(|r| r.kind == ResId::Id(RT_ICON) && r.name == ResId::Id(id))
+            .map(|r| r.data.as_ref())
+    };
+    let mut groups: Vec<IconGroup> = Vec::new();
+    for group in all.iter().filter(|r| r.kind == ResId::Id(RT_GROUP_ICON)) {
+        // Language variants of one group usually share images; keep the first.
+        if groups.iter().any(|g| g.name == group.name) {
+            continue;
+        }
+        let mut images = Vec::new();
+        for (fields, id) in group_members(&group.data, &group.name)? {
+            let Some(image) = icon(id) else {
+                bail!(IconError::Malformed(format!(
+                    "Icon group {} references missing RT_ICON #{}",
+                    group.name, id
+                )));
+            };
+            images.push((fields, image));
+        }
+        let mut ico = Vec::new();
+        ico.extend(0u16.to_le_bytes());
+        ico.extend(1u16.to_le_bytes());
+        ico.extend((images.len() as u16).to_le_bytes());
+        let mut offset = 6 + 16 * images.len() as u32;
+        for (fields, image) in &images {
+            ico.extend(&fields[..8]);
+            ico.extend((image.len() as u32).to_le_bytes());
+            ico.extend(offset.to_le_bytes());
+            offset += image.len() as u32;
+        }
+        for (_, image) in &images {
+            ico.extend(*image);
+        }
+        groups.push(IconGroup {
+            name: group.name.clone(),
+            ico,
+        });
+    }
+    Ok(groups)
+}
+
+// Type → name → language tree over indices into the sorted resource list.
+type Tree<'r> = Vec<(&'r ResId, Vec<(&'r ResId, Vec<usize>)>)>;
+
+fn dir_size(entries: usize) -> usize {
+    16 + 8 * entries
+}
+
+fn write_dir(out: &mut Vec<u8>, entries: &[(u32, u32)]) {
+    let named = entries.iter().filter(|(n, _)| n & 0x8000_0000 != 0).count();
+    out.extend([0u8; 12]); // characteristics, timestamp, version
+    out.extend((named as u16).to_le_bytes());
+    out.extend(((entries.len() - named) as u16).to_le_bytes());
+    for (name, target) in entries {
+        out.extend(name.to_le_bytes());
+        out.extend(target.to_le_bytes());
+    }
+}
+
+// Serialize a resource tree as a section mapped at `va`. Layout: every directory table
+// (breadth-first), then the data entries, then name strings, then the resource data,
+// each blob 8-byte aligned.
+fn build_resource_section(resources: &[Resource], va: u32) -> Vec<u8> {
+    let mut sorted: Vec<&Resource> = resources.iter().collect();
+    sorted.sort_by_key(|r| (r.kind.sort_key(), r.name.sort_key(), r.lang));
+    let mut tree: Tree = Vec::new();
+    for (i, r) in sorted.iter().enumerate() {
+        if tree.last().is_none_or(|(kind, _)| *kind != &r.kind) {
+            tree.push((&r.kind, Vec::new()));
+        }
+        let names = &mut tree.last_mut().unwrap().1;
+        if names.last().is_none_or(|(name, _)| *name != &r.name) {
+            names.push((&r.name, Vec::new()));
+        }
+        names.last_mut().unwrap().1.push(i);
+    }
+
+    let mut name_dirs = Vec::new();
+    let mut next = dir_size(tree.len());
+    for (_, names) in &tree {
+        name_dirs.push(next);
+        next += dir_size(names.len());
+    }
+    let mut lang_dirs = Vec::new();
+    for (_, names) in &tree {
+        for (_, leaves) in names {
+            lang_dirs.push(next);
+            next += dir_size(leaves.len());
+        }
+    }
+    let entries_at = next;
+    let strings_at = entries_at + 16 * sorted.len();
+
+    let mut strings: Vec<u8> = Vec::new();
+    let mut string_offsets: Vec<(String, usize)> = Vec::new();
+    let mut entry_name = |id: &ResId| -> u32 {
+        let name = match id {
+            ResId::Id(id) => return *id,
+            ResId::Name(name) => name,
+        };
+        let off = match string_offsets.iter().find(|(n, _)| n == name) {
+            Some((_, off)) => *off,
+            None => {
+                let off = strings_at + strings.len();
+                let units: Vec<u16> = name.encode_utf16().collect();
+                strings.extend((units.len() as u16).to_le_bytes());
+                units.iter().for_each(|u| strings.extend(u.to_le_bytes()));
+                string_offsets.push((name.clone(), off));
+                off
+            }
+        };
+        0x8000_0000 | off as u32
+    };
+
+    let mut out: Vec<u8> = Vec::with_capacity(entries_at);
+    let root: Vec<(u32, u32)> = tree
+        .iter()
+        .zip(&name_dirs)
+        .map(|((kind, _), &at)| (entry_name(kind), 0x8000_0000 | at as u32))
+        .collect();
+    write_dir(&mut out, &root);
+    let mut lang_dir = lang_dirs.iter();
+    for (_, names) in &tree {
+        let table: Vec<(u32, u32)> = names
+            .iter()
+            .map(|(name, _)| {
+                let at = *lang_dir.next().unwrap();
+                (entry_name(name), 0x8000_0000 | at as u32)
+            })
+            .collect();
+        write_dir(&mut out, &table);
+    }
+    for (_, names) in &tree {
+        for (_, leaves) in names {
+            let table: Vec<(u32, u32)> = leaves
+                .iter()
+                .map(|&i| (sorted[i].lang, (entries_at + 16 * i) as u32))
+                .collect();
+            write_dir(&mut out, &table);
+        }
+    }
+
+    let data_at = align((strings_at + strings.len()) as u32, 8) as usize;
+    let mut blobs: Vec<u8> = Vec::new();
+    for r in &sorted {
+        let rva = va + (data_at + blobs.len()) as u32;
+        out.extend(rva.to_le_bytes());
+        out.extend((r.data.len() as u32).to_le_bytes());
+        out.extend([0u8; 8]); // code page, reserved
+        blobs.extend(r.data.iter());
+        blobs.resize(align(blobs.len() as u32, 8) as usize, 0);
+    }
+    out.extend(strings);
+    out.resize(data_at, 0);
+    out.extend(blobs);
+    out
+}
+
+/// Result of `embed_icon`.
+pub struct Embedded {
+    pub image: Vec<u8>,
+    /// Icon group that was replaced (or created).
+    pub group: ResId,
+    /// The file carried an Authenticode signature, which had to be dropped.
+    pub stripped_signature: bool,
+}
+
+// Replace the first icon group (the one Explorer shows) with the images of `ico`, or
+// add group #1 when the file has none. The whole resource section is rebuilt: in place
+// when it is the last section of the image, otherwise as a new section appended after
+// the others (the old one is left behind, unreferenced).
+pub fn embed_icon(data: &[u8], ico: &[u8]) -> Result<Embedded> {
+    if ico.len() < 6 || u16_at(ico, 0)? != 0 || u16_at(ico, 2)? != 1 {
+        bail!(IconError::Malformed("Not an .ico file".into()));
+    }
+    let h = parse_headers(data)?;
+    if h.data_dir_count <= 4 {
+        bail!(IconError::Unsupported(
+            "PE header has no resource directory slot".into()
+        ));
+    }
+    let mut res = resources(data)?;
+    let (group, lang) = res
+        .iter()
+        .find(|r| r.kind == ResId::Id(RT_GROUP_ICON))
+        .map(|r| (r.name.clone(), r.lang))
+        .unwrap_or((ResId::Id(1), LANG_NEUTRAL));
+
+    // Drop the old group (every language) and the images no other group shares.
+    let is_group = |r: &Resource| r.kind == ResId::Id(RT_GROUP_ICON);
+    let mut old_members = Vec::new();
+    for r in res.iter().filter(|r| is_group(r) && r.name == group) {
+        old_members.extend(group_members(&r.data, &r.name)?.into_iter().map(|m| m.1));
+    }
+    res.retain(|r| !is_group(r) || r.name != group);
+    let mut shared = Vec::new();
+    for r in res.iter().filter(|r| is_group(r)) {
+        shared.extend(group_members(&r.data, &r.name)?.into_iter().map(|m| m.1));
+    }
+    res.retain(|r| match (&r.kind, &r.name) {
+        (ResId::Id(RT_ICON), ResId::Id(id)) => !old_members.contains(id) || shared.contains(id),
+        _ => true,
+    });
+
+    let first_id = res
+        .iter()
+        .filter_map(|r| match (&r.kind, &r.name) {
+            (ResId::Id(RT_ICON), ResId::Id(id)) => Some(*id),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let count = u16_at(ico, 4)? as usize;
+    let mut group_dir = ico[..6].to_vec();
+    for (i, id) in (first_id..first_id + count as u32).enumerate() {
+        let e = 6 + i * 16;
+        let size = u32_at(ico, e + 8)? as usize;
+        let offset = u32_at(ico, e + 12)? as usize;
+        let Some(image) = ico.get(offset..offset.saturating_add(size)) else {
+            bail!(IconError::Malformed(format!(
+                "Icon entry #{} is truncated",
+                i
+            )));
+        };
+        if id > u16::MAX as u32 {
+            bail!(IconError::Unsupported("No free RT_ICON IDs left".into()));
+        }
+        group_dir.extend(&ico[e..e + 12]);
+        group_dir.extend((id as u16).to_le_bytes());
+        res.push(Resource {
+            kind: ResId::Id(RT_ICON),
+            name: ResId::Id(id),
+            lang,
+            data: Cow::Owned(image.to_vec()),
+        });
+    }
+    res.push(Resource {
+        kind: ResId::Id(RT_GROUP_ICON),
+        name: group.clone(),
+        lang,
+        data: Cow::Owned(group_dir),
+    });
+
+    let mut image = data.to_vec();
+    // The certificate table (data directory #4) is addressed by file offset and always
+    // sits at the very end; any rewrite invalidates the signature anyway.
+    let security = h.data_dirs + 4 * 8;
+    let stripped_signature = u32_at(&image, security + 4)? != 0;
+    if stripped_signature {
+        let cert_offset = u32_at(&image, security)? as usize;
+        image.truncate(cert_offset.min(image.len()));
+        put_u32(&mut image, security, 0);
+        put_u32(&mut image, security + 4, 0);
+    }
+    let section_alignment = u32_at(&image, h.optional + 32)?;
+    let file_alignment = u32_at(&image, h.optional + 36)?;
+    let Some(last) = h.sections.iter().max_by_key(|s| s.raw_offset + s.raw_size) else {
+        bail!(IconError::Malformed("PE file has no sections".into()));
+    };
+    if image.len() > (last.raw_offset + last.raw_size) as usize {
+        bail!(IconError::Unsupported(
+            "PE file has data appended after its last section".into()
+        ));
+    }
+    let top_va = h
+        .sections
+        .iter()
+        .map(|s| s.virtual_address + s.virtual_size.max(s.raw_size))
+        .max()
+        .unwrap_or(0);
+    let in_place = h.resource_rva != 0
+        && last.virtual_address == h.resource_rva
+        && last.virtual_address + last.virtual_size.max(last.raw_size) == top_va;
+    let (va, raw_offset, header) = if in_place {
+        (last.virtual_address, last.raw_offset, last.header_offset)
+    } else {
+        let first_raw = h
+            .sections
+            .iter()
+            .map(|s| s.raw_offset as usize)
+            .filter(|&o| o > 0)
+            .min()
+            .unwrap_or(image.len());
+        if h.section_table_end + 40 > first_raw {
+            bail!(IconError::Unsupported(
+                "No room in the PE header for a new resource section".into()
+            ));
+        }
+        let header = h.section_table_end;
+        image[header..header + 40].fill(0);
+        image[header..header + 8].copy_from_slice(b".rsrc\0\0\0");
+        put_u32(&mut image, header + 36, RSRC_CHARACTERISTICS);
+        put_u16(&mut image, h.coff + 2, h.sections.len() as u16 + 1);
+        (
+            align(top_va, section_alignment),
+            align(image.len() as u32, file_alignment),
+            header,
+        )
+    };
+
+    let section = build_resource_section(&res, va);
+    let raw_size = align(section.len() as u32, file_alignment);
+    image.resize(raw_offset as usize, 0);
+    image.extend(&section);
+    image.resize((raw_offset + raw_size) as usize, 0);
+    put_u32(&mut image, header + 8, section.len() as u32);
+    put_u32(&mut image, header + 12, va);
+    put_u32(&mut image, header + 16, raw_size);
+    put_u32(&mut image, header + 20, raw_offset);
+    put_u32(&mut image, h.data_dirs + 2 * 8, va);
+    put_u32(&mut image, h.data_dirs + 2 * 8 + 4, section.len() as u32);
+    put_u32(
+        &mut image,
+        h.optional + 56,
+        align(va + section.len() as u32, section_alignment),
+    );
+    let checksum = pe_checksum(&image, h.optional + 64);
+    put_u32(&mut image, h.optional + 64, checksum);
+    Ok(Embedded {
+        image,
+        group,
+        stripped_signature,
+    })
+}
+
+// The loader's image checksum: a folded 16-bit sum of the file, skipping the checksum
+// field itself, plus the file length.
+fn pe_checksum(data: &[u8], checksum_at: usize) -> u32 {
+    let mut sum: u32 = 0;
+    for (i, word) in data.chunks(2).enumerate() {
+        if i * 2 == checksum_at || i * 2 == checksum_at + 2 {
+            continue;
+        }
+        sum += u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum + data.len() as u32
+}